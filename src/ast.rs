@@ -1,16 +1,42 @@
 use std::any::{Any, TypeId};
+use std::cmp::Ordering;
 
-use crate::util::{HashSet, Symbol};
+use crate::util::{HashSet, IndexMap, Symbol};
 
-#[derive(Default, Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+/// Values are ordered as signed integers, like aggregates compare them.
+#[derive(Default, Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub struct Value(u64);
 
+impl Value {
+    /// The raw bits, which is how values are saved.
+    pub(crate) fn to_bits(self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn from_bits(bits: u64) -> Self {
+        Value(bits)
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0 as i64).cmp(&(other.0 as i64))
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 pub type Variable = Symbol;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Term {
     Variable(Variable),
     Value(Value),
+    Aggregate(Aggregate, Variable),
 }
 
 impl Term {
@@ -18,10 +44,40 @@ impl Term {
         match self {
             Term::Variable(v) => panic!("Can't eval a variable {}", v),
             Term::Value(val) => *val,
+            Term::Aggregate(..) => panic!("Can't eval an aggregate {:?}", self),
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    /// Folds the values a variable takes in each substitution of a group.
+    /// Values are summed and compared as signed integers.
+    pub fn apply(self, values: &[Value]) -> Value {
+        let ints = values.iter().map(|v| v.0 as i64);
+        let n = match self {
+            Aggregate::Count => values.len() as i64,
+            Aggregate::Sum => ints.fold(0, i64::wrapping_add),
+            Aggregate::Min => ints.min().expect("Can't take the min of no values"),
+            Aggregate::Max => ints.max().expect("Can't take the max of no values"),
+        };
+        Value(n as u64)
+    }
+
+    /// Whether the aggregate of no values is defined, which it is for count
+    /// and sum.
+    pub fn has_empty_value(self) -> bool {
+        matches!(self, Aggregate::Count | Aggregate::Sum)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Atom {
     pub relation: Symbol,
@@ -39,6 +95,35 @@ impl Atom {
     pub fn has_var(&self, v: Variable) -> bool {
         self.terms.contains(&Term::Variable(v))
     }
+
+    pub fn has_aggregate(&self) -> bool {
+        self.terms.iter().any(|t| matches!(t, Term::Aggregate(..)))
+    }
+
+    /// Whether the atom aggregates over every substitution as one group,
+    /// which has a tuple even when there are none. That is when it groups
+    /// by no variable and each of its aggregates is defined for no values.
+    pub fn has_empty_group(&self) -> bool {
+        self.has_aggregate()
+            && self.terms.iter().all(|t| match t {
+                Term::Variable(_) => false,
+                Term::Aggregate(agg, _) => agg.has_empty_value(),
+                Term::Value(_) => true,
+            })
+    }
+
+    /// Whether a tuple has the atom's values, and equal values in the
+    /// columns of each repeated variable.
+    pub fn matches(&self, tuple: &[Value]) -> bool {
+        let mut bindings: IndexMap<Variable, Value> = IndexMap::default();
+        self.terms
+            .iter()
+            .zip(tuple)
+            .all(|(term, &value)| match term {
+                Term::Variable(v) => *bindings.entry(*v).or_insert(value) == value,
+                _ => term.eval() == value,
+            })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -47,15 +132,107 @@ pub struct Rule {
     pub body: Query,
 }
 
+impl Rule {
+    /// Aggregate rules group the substitutions of their body by the plain
+    /// variables of the head.
+    pub fn is_aggregate(&self) -> bool {
+        self.head.iter().any(Atom::has_aggregate)
+    }
+
+    /// Returns the first variable of a head atom that no positive body atom
+    /// binds, along with the head's relation, if there is one.
+    pub fn unbound_head_var(&self) -> Option<(Symbol, Variable)> {
+        self.head.iter().find_map(|atom| {
+            let mut vars = atom.terms.iter().filter_map(|t| match t {
+                Term::Variable(v) | Term::Aggregate(_, v) => Some(*v),
+                Term::Value(_) => None,
+            });
+            let unbound = vars.find(|&v| !self.body.atoms.iter().any(|a| a.has_var(v)));
+            unbound.map(|v| (atom.relation, v))
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Literal {
+    Positive(Atom),
+    Negative(Atom),
+}
+
 #[derive(Debug, Clone)]
 pub struct Query {
     pub atoms: Vec<Atom>,
+    pub negated: Vec<Atom>,
+}
+
+impl Query {
+    /// Builds a query from body literals. Every `_` wildcard becomes a fresh
+    /// variable, so wildcards never join with each other. In a negated atom,
+    /// a wildcard does not have to be bound: the atom is false if any tuple
+    /// matches its other terms.
+    pub fn new(literals: Vec<Literal>) -> Self {
+        let mut query = Query {
+            atoms: vec![],
+            negated: vec![],
+        };
+        let wildcard = Symbol::new("_");
+        let mut fresh = 0;
+        for lit in literals {
+            let (negated, mut atom) = match lit {
+                Literal::Positive(atom) => (false, atom),
+                Literal::Negative(atom) => (true, atom),
+            };
+            for term in &mut atom.terms {
+                if *term == Term::Variable(wildcard) {
+                    *term = Term::Variable(Symbol::new(format!("_#{}", fresh)));
+                    fresh += 1;
+                }
+            }
+            match negated {
+                false => query.atoms.push(atom),
+                true => query.negated.push(atom),
+            }
+        }
+        query
+    }
+
+    /// Returns the first variable of a negated atom that no positive atom
+    /// binds, if there is one. Wildcards don't need to be bound.
+    pub fn unsafe_var(&self) -> Option<Variable> {
+        self.negated
+            .iter()
+            .flat_map(|atom| atom.vars())
+            .filter(|&v| !is_wildcard(v))
+            .find(|&v| !self.atoms.iter().any(|atom| atom.has_var(v)))
+    }
+}
+
+/// Whether a variable is one of the fresh variables `_` wildcards become.
+pub fn is_wildcard(var: Variable) -> bool {
+    var.as_ref().starts_with("_#")
 }
 
 #[derive(Debug, Clone)]
 pub struct Relation {
     pub symbol: Symbol,
     pub schema: Schema,
+    pub key: Option<Key>,
+    // the reflexive, symmetric and transitive closure of its tuples
+    pub equivalence: bool,
+}
+
+/// A functional dependency: the key columns determine the other columns,
+/// and tuples that only differ in those are merged.
+#[derive(Debug, Clone)]
+pub struct Key {
+    pub columns: Vec<Symbol>,
+    pub merge: Merge,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Merge {
+    Min,
+    Max,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -72,6 +249,11 @@ impl Schema {
         self.types.len()
     }
 
+    /// The position of the column with this name.
+    pub fn index_of(&self, name: Symbol) -> Option<usize> {
+        self.types.iter().position(|&(s, _t)| s == name)
+    }
+
     pub fn from_types(types: &[TypeId]) -> Self {
         let types: Vec<_> = types
             .iter()
@@ -81,6 +263,11 @@ impl Schema {
         Self { types }
     }
 
+    /// The name and type of each column.
+    pub fn columns(&self) -> &[(Symbol, TypeId)] {
+        &self.types
+    }
+
     pub fn from_named_types(types: Vec<(Symbol, TypeId)>) -> Self {
         let mut names = HashSet::default();
         for &(s, _t) in &types {
@@ -117,6 +304,7 @@ pub struct Program {
     pub relations: Vec<Relation>,
     pub facts: Vec<Atom>,
     pub directives: Vec<Directive>,
+    pub queries: Vec<Query>,
 }
 
 // TODO rename
@@ -125,16 +313,23 @@ pub trait Type: Any {
         TypeId::of::<Self>()
     }
     fn to_value(self) -> Value;
+    fn from_value(value: Value) -> Self;
 }
 
 impl Type for i32 {
     fn to_value(self) -> Value {
         Value(self as u64)
     }
+
+    fn from_value(value: Value) -> Self {
+        value.0 as i32
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_schema() {
         let s1 = schema!(a: i32, b: i32);
@@ -149,4 +344,13 @@ mod tests {
         let s5 = schema!(i32, i32);
         assert_eq!(s4, s5);
     }
+
+    #[test]
+    fn test_aggregate() {
+        let values: Vec<Value> = [3, -2, 5].iter().map(|&i: &i32| i.to_value()).collect();
+        assert_eq!(Aggregate::Count.apply(&values), 3.to_value());
+        assert_eq!(Aggregate::Sum.apply(&values), 6.to_value());
+        assert_eq!(Aggregate::Min.apply(&values), (-2).to_value());
+        assert_eq!(Aggregate::Max.apply(&values), 5.to_value());
+    }
 }
@@ -0,0 +1,161 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::Error;
+
+/// Limits on a single [`run`](crate::DatalogContext::run). They are checked
+/// between semi-naive iterations, and the timeout, the derived tuples, and
+/// cancellation also while an iteration joins. An iteration that is stopped
+/// halfway derives nothing, so the run ends where the one before it did.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    /// The number of iterations that read new tuples, across all strata.
+    pub max_iterations: Option<usize>,
+    pub timeout: Option<Duration>,
+    /// The number of tuples added to relations. While an iteration joins,
+    /// each substitution of a rule body counts as a tuple, since it can
+    /// derive one.
+    pub max_derived: Option<usize>,
+}
+
+/// Stops the current or next run of the context it came from, see
+/// [`cancellation_token`](crate::DatalogContext::cancellation_token). It can
+/// be cloned and sent to other threads.
+#[derive(Default, Debug, Clone)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The limit that stopped a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Iterations,
+    Time,
+    Derived,
+    Cancelled,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::Iterations => write!(f, "the iteration limit"),
+            Limit::Time => write!(f, "the timeout"),
+            Limit::Derived => write!(f, "the derived tuple limit"),
+            Limit::Cancelled => write!(f, "cancellation"),
+        }
+    }
+}
+
+/// How a run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outcome {
+    /// The number of tuples added.
+    pub derived: usize,
+    /// The limit that stopped the run before every stratum reached a
+    /// fixpoint, if one did.
+    pub stopped: Option<Limit>,
+}
+
+impl Outcome {
+    pub fn is_fixpoint(&self) -> bool {
+        self.stopped.is_none()
+    }
+
+    /// The number of tuples added, or an error if the run stopped early.
+    pub fn fixpoint(self) -> Result<usize, Error> {
+        match self.stopped {
+            Some(limit) => Err(Error::StoppedEarly(limit)),
+            None => Ok(self.derived),
+        }
+    }
+}
+
+/// What a run has used of its budget so far.
+pub(crate) struct Meter {
+    budget: Budget,
+    token: CancellationToken,
+    started: Instant,
+    iterations: usize,
+    derived: usize,
+}
+
+impl Meter {
+    pub fn new(budget: Budget, token: CancellationToken) -> Self {
+        Self {
+            budget,
+            token,
+            started: Instant::now(),
+            iterations: 0,
+            derived: 0,
+        }
+    }
+
+    /// Returns the limit that was hit, if any, before another iteration.
+    /// Hitting the cancellation limit resets the token.
+    pub fn check(&self) -> Result<(), Limit> {
+        let over = |max: Option<usize>, used| max.is_some_and(|max| used >= max);
+        if over(self.budget.max_iterations, self.iterations) {
+            return Err(Limit::Iterations);
+        }
+        if over(self.budget.max_derived, self.derived) {
+            return Err(Limit::Derived);
+        }
+        if let Some(timeout) = self.budget.timeout {
+            if self.started.elapsed() >= timeout {
+                return Err(Limit::Time);
+            }
+        }
+        if self.token.0.swap(false, Ordering::Relaxed) {
+            return Err(Limit::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Returns the limit that was hit, if any, in the middle of an iteration
+    /// that produced `substs` substitutions so far. Cancellation is left for
+    /// [`check`](Self::check) to reset, so every thread that is joining sees
+    /// it.
+    pub fn interrupted(&self, substs: usize) -> Option<Limit> {
+        if let Some(max) = self.budget.max_derived {
+            if self.derived + substs > max {
+                return Some(Limit::Derived);
+            }
+        }
+        if let Some(timeout) = self.budget.timeout {
+            if self.started.elapsed() >= timeout {
+                return Some(Limit::Time);
+            }
+        }
+        self.token.is_cancelled().then_some(Limit::Cancelled)
+    }
+
+    /// Resets the token if the run stopped because it was cancelled.
+    pub fn stopped(&self, limit: Limit) -> Limit {
+        if limit == Limit::Cancelled {
+            self.token.0.store(false, Ordering::Relaxed);
+        }
+        limit
+    }
+
+    pub fn record(&mut self, derived: usize) {
+        self.iterations += 1;
+        self.derived += derived;
+    }
+
+    pub fn outcome(&self, stopped: Option<Limit>) -> Outcome {
+        Outcome {
+            derived: self.derived,
+            stopped,
+        }
+    }
+}
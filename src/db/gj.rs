@@ -1,37 +1,161 @@
+use std::convert::Infallible;
+use std::ops::Bound;
+
 use super::*;
 
 use crate::util::IndexMap;
 
-#[derive(Default, Debug, Clone)]
-struct Trie(IndexMap<Value, Self>);
+use super::trie::{Trie, TrieRef};
+use rayon::prelude::*;
 
-impl Trie {
-    fn len(&self) -> usize {
-        self.0.len()
-    }
+// for each var, says which atoms contain it
+pub(crate) type VarOccurences = IndexMap<Variable, Vec<usize>>;
+
+// how to build the trie for an atom
+#[derive(Clone)]
+struct AtomPlan {
+    // the columns of the trie in order: the constants, then the variables
+    shuffle: Vec<usize>,
+    constants: Vec<Value>,
+    // pairs of columns that hold the same variable
+    eq_constraints: Vec<(usize, usize)>,
 }
 
-impl Trie {
-    fn insert(&mut self, shuffle: &[usize], tuple: &[Value]) {
-        debug_assert_eq!(shuffle.len(), tuple.len());
-        let mut trie = self;
-        for i in shuffle {
-            trie = trie.0.entry(tuple[*i]).or_default();
+impl AtomPlan {
+    fn new(atom: &Atom, by_var: &VarOccurences) -> Self {
+        let mut eq_constraints = vec![];
+        for (i, term) in atom.terms.iter().enumerate() {
+            if let Term::Variable(_) = term {
+                if let Some(j) = atom.terms.iter().position(|t| t == term) {
+                    if i != j {
+                        eq_constraints.push((j, i));
+                    }
+                }
+            }
+        }
+
+        // constants go first in the trie, so they can be looked up
+        // before the join starts
+        let mut shuffle = vec![];
+        let mut constants = vec![];
+        for (i, term) in atom.terms.iter().enumerate() {
+            if let Term::Value(val) = term {
+                shuffle.push(i);
+                constants.push(*val);
+            }
+        }
+        for var in by_var.keys() {
+            if let Some(i) = atom.terms.iter().position(|t| t == &Term::Variable(*var)) {
+                shuffle.push(i);
+            }
+        }
+
+        Self {
+            shuffle,
+            constants,
+            eq_constraints,
         }
     }
+
+    /// Only atoms without repeated variables can use a relation's indexes,
+    /// since the indexes hold every tuple.
+    fn can_use_index(&self) -> bool {
+        self.eq_constraints.is_empty()
+    }
+
+    fn build_trie<'a>(&self, tuples: impl Iterator<Item = (usize, &'a [Value])>) -> Trie {
+        let mut trie = Trie::default();
+        for (row, tuple) in tuples {
+            if self
+                .eq_constraints
+                .iter()
+                .all(|(i, j)| tuple[*i] == tuple[*j])
+            {
+                trie.insert(&self.shuffle, tuple, row);
+            }
+        }
+        trie
+    }
 }
 
-// for each var, says which atoms contain it
-type VarOccurences = IndexMap<Variable, Vec<usize>>;
+// how to check a negated atom
+#[derive(Clone)]
+struct NegatedPlan {
+    // how many variables must be bound to check it
+    depth: usize,
+    // the columns that are not wildcards, then the wildcards
+    shuffle: Vec<usize>,
+    // how many columns are not wildcards
+    bound: usize,
+}
+
+impl NegatedPlan {
+    // wildcards are checked by looking up the other columns as a prefix of
+    // the relation's index in `shuffle` order
+    fn has_wildcards(&self) -> bool {
+        self.bound < self.shuffle.len()
+    }
+}
 
 #[derive(Clone)]
 pub struct CompiledQuery {
     query: Query,
+    versions: Vec<Version>,
+    plans: Vec<AtomPlan>,
     pub by_var: VarOccurences,
+    negated: Vec<NegatedPlan>,
+    // the sizes of the relations when the variables were last ordered
+    planned_sizes: Vec<usize>,
 }
 
+// replan once a relation grew or shrank by this factor
+const REPLAN_FACTOR: usize = 4;
+
 impl CompiledQuery {
-    pub fn new(_db: &Database, query: Query) -> Self {
+    pub fn new(db: &Database, query: Query, versions: Vec<Version>) -> Self {
+        assert_eq!(query.atoms.len(), versions.len());
+        let mut cq = Self {
+            query,
+            versions,
+            plans: vec![],
+            by_var: Default::default(),
+            negated: vec![],
+            planned_sizes: vec![],
+        };
+        cq.plan(db, &Deltas::default());
+        cq
+    }
+
+    // the size of each atom's input, recent atoms only read their delta
+    fn sizes(&self, db: &Database, deltas: &Deltas) -> Vec<usize> {
+        let atoms = self.query.atoms.iter().zip(&self.versions);
+        atoms
+            .map(|(atom, &version)| match version {
+                Version::Recent => deltas.get(&atom.relation).map_or(0, |r| r.len()),
+                _ => db.relations[&atom.relation].rows.len(),
+            })
+            .collect()
+    }
+
+    /// Whether a relation changed size enough since the last plan that the
+    /// variable order should be recomputed. Deltas change every iteration, so
+    /// only the relations read in full are considered.
+    pub(crate) fn needs_replan(&self, db: &Database) -> bool {
+        let sizes = self.sizes(db, &Deltas::default());
+        let versions = self.versions.iter();
+        let changed = sizes.iter().zip(&self.planned_sizes).zip(versions);
+        changed
+            .filter(|(_, &version)| version != Version::Recent)
+            .any(|((&now, &then), _)| {
+                let (now, then) = (now.max(1), then.max(1));
+                now >= then * REPLAN_FACTOR || then >= now * REPLAN_FACTOR
+            })
+    }
+
+    /// Orders the variables using the current statistics of the database and
+    /// sizes of the deltas, and recomputes how to build each atom's trie.
+    pub(crate) fn plan(&mut self, db: &Database, deltas: &Deltas) {
+        let query = &self.query;
         let mut by_var = VarOccurences::default();
         for (i, atom) in query.atoms.iter().enumerate() {
             for v in atom.vars() {
@@ -42,76 +166,343 @@ impl CompiledQuery {
             }
         }
 
-        // simple variable ordering for now
-        by_var.sort_by(|_v1, occ1, _v2, occ2| occ1.len().cmp(&occ2.len()).reverse());
+        let sizes = self.sizes(db, deltas);
+        let by_var = planner::order_vars(db, query, &sizes, by_var);
 
-        if cfg!(debug_assert) {
+        if cfg!(debug_assertions) {
             for (&var, ats) in &by_var {
                 let expected: Vec<usize> = query
                     .atoms
                     .iter()
                     .enumerate()
-                    .filter_map(|(i, a)| a.has_var(var).then(|| i))
+                    .filter(|(_, a)| a.has_var(var))
+                    .map(|(i, _)| i)
                     .collect();
                 debug_assert_eq!(ats, &expected)
             }
         }
 
-        Self { query, by_var }
+        self.negated = query
+            .negated
+            .iter()
+            .map(|atom| {
+                let is_wildcard = |t: &Term| matches!(t, Term::Variable(v) if is_wildcard(*v));
+                let depth = atom
+                    .vars()
+                    .filter(|&v| !is_wildcard(&Term::Variable(v)))
+                    .map(|v| match by_var.get_index_of(&v) {
+                        Some(i) => i + 1,
+                        None => panic!("Variable {} in negated atom is not bound", v),
+                    })
+                    .max()
+                    .unwrap_or(0);
+                let (mut shuffle, wildcards): (Vec<usize>, Vec<usize>) =
+                    (0..atom.terms.len()).partition(|&i| !is_wildcard(&atom.terms[i]));
+                let bound = shuffle.len();
+                shuffle.extend(wildcards);
+                NegatedPlan {
+                    depth,
+                    shuffle,
+                    bound,
+                }
+            })
+            .collect();
+
+        self.plans = query
+            .atoms
+            .iter()
+            .map(|atom| AtomPlan::new(atom, &by_var))
+            .collect();
+        self.by_var = by_var;
+        self.planned_sizes = self.sizes(db, &Deltas::default());
+    }
+
+    /// The indexes this query reads, as relations and column orders.
+    pub(crate) fn indexes(&self) -> impl Iterator<Item = (Symbol, &[usize])> + '_ {
+        let atoms = self.query.atoms.iter().zip(&self.versions);
+        let negated = self.query.negated.iter().zip(&self.negated);
+        atoms
+            .zip(&self.plans)
+            .filter(|((_atom, &version), plan)| version != Version::Recent && plan.can_use_index())
+            .map(|((atom, _version), plan)| (atom.relation, plan.shuffle.as_slice()))
+            .chain(
+                negated
+                    .filter(|(_atom, plan)| plan.has_wildcards())
+                    .map(|(atom, plan)| (atom.relation, plan.shuffle.as_slice())),
+            )
     }
 
     pub(crate) fn get_index(&self, var: Symbol) -> usize {
         self.by_var.get_index_of(&var).unwrap()
     }
 
-    pub fn eval<F>(&self, db: &Database, mut f: F)
+    pub fn eval<F>(&self, db: &Database, deltas: &Deltas, mut f: F)
     where
         F: FnMut(&[Value]),
     {
-        let tries = self
-            .query
-            .atoms
-            .iter()
-            .map(|atom| {
-                let mut eq_constraints = vec![];
-                for (i, term) in atom.terms.iter().enumerate() {
-                    if let Term::Variable(_) = term {
-                        if let Some(j) = atom.terms.iter().position(|t| t == term) {
-                            eq_constraints.push((j, i));
+        self.with_tries(db, deltas, None, |tries, negated| {
+            if !self.is_negated(negated, &[]) {
+                self.gj_all(&mut f, &[], tries, negated);
+            }
+        });
+    }
+
+    /// Like [`eval`](Self::eval), but the `Recent` atoms read the given
+    /// tuples instead of a range of rows.
+    pub fn eval_with_tuples<F>(&self, db: &Database, tuples: &Tuples, mut f: F)
+    where
+        F: FnMut(&[Value]),
+    {
+        let deltas = Deltas::default();
+        self.with_tries(db, &deltas, Some(tuples), |tries, negated| {
+            if !self.is_negated(negated, &[]) {
+                self.gj_all(&mut f, &[], tries, negated);
+            }
+        });
+    }
+
+    /// Like [`eval`](Self::eval), but splits the values of the first variable
+    /// into chunks that are joined on rayon's worker threads. Each chunk
+    /// sends its results to its own sink, and the sinks are returned in the
+    /// order of the chunks, so the results are the same as a sequential run.
+    pub fn par_eval<T, F>(&self, db: &Database, deltas: &Deltas, f: F) -> Vec<T>
+    where
+        T: Default + Send,
+        F: Fn(&mut T, &[Value]) + Sync,
+    {
+        let f = |sink: &mut T, tuple: &[Value]| -> ControlFlow<Infallible> {
+            f(sink, tuple);
+            ControlFlow::Continue(())
+        };
+        match self.try_par_eval(db, deltas, f) {
+            ControlFlow::Continue(sinks) => sinks,
+            ControlFlow::Break(never) => match never {},
+        }
+    }
+
+    /// Like [`par_eval`](Self::par_eval), but the join stops as soon as `f`
+    /// breaks on any thread, and returns what it broke with instead of the
+    /// sinks.
+    pub fn try_par_eval<T, B, F>(
+        &self,
+        db: &Database,
+        deltas: &Deltas,
+        f: F,
+    ) -> ControlFlow<B, Vec<T>>
+    where
+        T: Default + Send,
+        B: Send,
+        F: Fn(&mut T, &[Value]) -> ControlFlow<B> + Sync,
+    {
+        let sinks = self.with_tries(db, deltas, None, |tries, negated| {
+            if self.is_negated(negated, &[]) {
+                return ControlFlow::Continue(vec![]);
+            }
+            if self.by_var.is_empty() {
+                let mut sink = T::default();
+                f(&mut sink, &[])?;
+                return ControlFlow::Continue(vec![sink]);
+            }
+
+            let intersection = self.intersect(0, tries);
+            let chunk_size = intersection.len() / (rayon::current_num_threads() * 4) + 1;
+            let sinks: Result<Vec<T>, B> = intersection
+                .par_chunks(chunk_size)
+                .map(|vals| {
+                    let mut sink = T::default();
+                    let mut f = |tuple: &[Value]| f(&mut sink, tuple);
+                    for &val in vals {
+                        let tries = self.descend(0, tries, val);
+                        if self.is_negated(negated, &[val]) {
+                            continue;
+                        }
+                        if let ControlFlow::Break(b) = self.gj(&mut f, &[val], &tries, negated) {
+                            return Err(b);
                         }
                     }
+                    Ok(sink)
+                })
+                .collect();
+            match sinks {
+                Ok(sinks) => ControlFlow::Continue(sinks),
+                Err(b) => ControlFlow::Break(b),
+            }
+        });
+        sinks.unwrap_or(ControlFlow::Continue(vec![]))
+    }
+
+    /// Builds or looks up a trie for each atom and calls `f` with them and
+    /// the negated relations. Returns `None` if an atom can't match anything.
+    /// If `recent` is given, `Recent` atoms read those tuples instead.
+    fn with_tries<R>(
+        &self,
+        db: &Database,
+        deltas: &Deltas,
+        recent: Option<&Tuples>,
+        f: impl FnOnce(&[TrieRef], &[&Relation]) -> R,
+    ) -> Option<R> {
+        let atoms = self.query.atoms.iter().zip(&self.versions);
+        let ranges: Vec<Range<usize>> = atoms
+            .map(|(atom, &version)| {
+                let n = db.relations[&atom.relation].rows.len();
+                let delta = deltas.get(&atom.relation).cloned().unwrap_or(n..n);
+                match version {
+                    Version::Stable => 0..delta.start,
+                    // the given tuples are all put in row 0
+                    Version::Recent if recent.is_some() => 0..1,
+                    Version::Recent => delta,
+                    Version::All => 0..delta.end,
                 }
+            })
+            .collect();
 
-                let mut shuffle = vec![];
-                for var in self.by_var.keys() {
-                    for (i, term) in atom.terms.iter().enumerate() {
-                        if let Term::Variable(v) = term {
-                            if var == v && !shuffle.contains(&i) {
-                                shuffle.push(i);
-                            }
-                        }
-                    }
+        // recent tuples and atoms that can't use an index get their own trie
+        let temps: Vec<Option<Trie>> = (0..self.plans.len())
+            .map(|i| {
+                let relation = &self.query.atoms[i].relation;
+                let rel = &db.relations[relation];
+                let plan = &self.plans[i];
+                if let (Version::Recent, Some(recent)) = (self.versions[i], recent) {
+                    let tuples = recent.get(relation).into_iter().flatten();
+                    Some(plan.build_trie(tuples.map(|t| (0, t.as_slice()))))
+                } else if self.versions[i] == Version::Recent || !plan.can_use_index() {
+                    Some(plan.build_trie(rel.rows.range(ranges[i].clone())))
+                } else {
+                    None
                 }
+            })
+            .collect();
+
+        let mut tries = Vec::with_capacity(self.plans.len());
+        for (i, plan) in self.plans.iter().enumerate() {
+            let rel = &db.relations[&self.query.atoms[i].relation];
+            let trie = match &temps[i] {
+                Some(trie) => trie,
+                None => rel.index(&plan.shuffle).expect("Index was not built"),
+            };
+            // no tuple matches the constants
+            tries.push(trie.view(ranges[i].end).get_path(&plan.constants)?);
+        }
+
+        let negated: Vec<&Relation> = self
+            .query
+            .negated
+            .iter()
+            .map(|atom| &db.relations[&atom.relation])
+            .collect();
+
+        Some(f(&tries, &negated))
+    }
 
-                let mut trie = Trie::default();
-                for tuple in &db.relations[&atom.relation].set {
-                    if eq_constraints.iter().all(|(i, j)| tuple[*i] == tuple[*j]) {
-                        trie.insert(&shuffle, tuple);
+    /// Checks the negated atoms that become fully bound with this tuple.
+    fn is_negated(&self, negated: &[&Relation], tuple: &[Value]) -> bool {
+        let atoms = self.query.negated.iter().zip(&self.negated);
+        atoms
+            .zip(negated)
+            .filter(|((_atom, plan), _rel)| plan.depth == tuple.len())
+            .any(|((atom, plan), rel)| {
+                let values: Vec<Value> = plan.shuffle[..plan.bound]
+                    .iter()
+                    .map(|&i| match &atom.terms[i] {
+                        Term::Variable(v) => tuple[self.get_index(*v)],
+                        Term::Value(val) => *val,
+                        Term::Aggregate(..) => unreachable!(),
+                    })
+                    .collect();
+                if !plan.has_wildcards() {
+                    return rel.contains(&values);
+                }
+                match (rel.is_equivalence(), values.as_slice()) {
+                    (_, []) => !rel.rows.is_empty(),
+                    // an element is in a class if it is equivalent to itself
+                    (true, &[v]) => rel.contains(&[v, v]),
+                    (_, prefix) => {
+                        let index = rel.index(&plan.shuffle).expect("Index was not built");
+                        index.view(rel.rows.len()).get_path(prefix).is_some()
                     }
                 }
-
-                trie
             })
-            .collect::<Vec<_>>();
+    }
 
-        let tries: Vec<&Trie> = tries.iter().collect();
+    /// The values of the `depth`th variable that every atom containing it
+    /// allows. The keys of the tries are sorted, so they are intersected by
+    /// leapfrogging: each trie in turn seeks to the largest key seen so far,
+    /// until they all land on the same one.
+    fn intersect(&self, depth: usize, relations: &[TrieRef]) -> Vec<Value> {
+        let (&x, js) = self.by_var.get_index(depth).unwrap();
+        debug_assert!(js.iter().all(|&j| self.query.atoms[j].has_var(x)));
 
-        self.gj(&mut f, &[], &tries);
+        let tries: Vec<&TrieRef> = js.iter().map(|&j| &relations[j]).collect();
+        let mut intersection = vec![];
+        let Some(mut candidate) = tries[0].seek(Bound::Unbounded) else {
+            return intersection;
+        };
+        // how many tries in a row have the candidate
+        let mut agreed = 1;
+        for trie in tries.iter().cycle().skip(1) {
+            let lower = match agreed == tries.len() {
+                true => {
+                    intersection.push(candidate);
+                    agreed = 0;
+                    Bound::Excluded(candidate)
+                }
+                false => Bound::Included(candidate),
+            };
+            match trie.seek(lower) {
+                None => break,
+                Some(val) if val == candidate => agreed += 1,
+                Some(val) => {
+                    candidate = val;
+                    agreed = 1;
+                }
+            }
+        }
+
+        intersection
     }
 
-    fn gj<F>(&self, f: &mut F, tuple: &[Value], relations: &[&Trie])
+    /// Binds the `depth`th variable to a value from its intersection.
+    fn descend<'a>(&self, depth: usize, relations: &[TrieRef<'a>], val: Value) -> Vec<TrieRef<'a>> {
+        let x = *self.by_var.get_index(depth).unwrap().0;
+        relations
+            .iter()
+            .zip(&self.query.atoms)
+            .map(|(r, a)| {
+                if a.has_var(x) {
+                    r.get(&val).expect("Value is not in the intersection")
+                } else {
+                    *r
+                }
+            })
+            .collect()
+    }
+
+    // joins every tuple, for callers that can't stop
+    fn gj_all<F>(&self, f: &mut F, tuple: &[Value], relations: &[TrieRef], negated: &[&Relation])
     where
         F: FnMut(&[Value]),
+    {
+        let mut f = |tuple: &[Value]| -> ControlFlow<Infallible> {
+            f(tuple);
+            ControlFlow::Continue(())
+        };
+        match self.gj(&mut f, tuple, relations, negated) {
+            ControlFlow::Continue(()) => (),
+            ControlFlow::Break(never) => match never {},
+        }
+    }
+
+    /// Extends a tuple of values of the first variables with every way to
+    /// bind the others, and calls `f` with each, until `f` breaks.
+    fn gj<B, F>(
+        &self,
+        f: &mut F,
+        tuple: &[Value],
+        relations: &[TrieRef],
+        negated: &[&Relation],
+    ) -> ControlFlow<B>
+    where
+        F: FnMut(&[Value]) -> ControlFlow<B>,
     {
         // println!("{:?}", tuple);
         if tuple.len() == self.by_var.len() {
@@ -120,48 +511,16 @@ impl CompiledQuery {
 
         assert!(tuple.len() < self.by_var.len());
 
-        let (&x, js) = self.by_var.get_index(tuple.len()).unwrap();
-        debug_assert!(js.iter().all(|&j| self.query.atoms[j].has_var(x)));
-
-        let j_min = js
-            .iter()
-            .copied()
-            .min_by_key(|j| relations[*j].len())
-            .unwrap();
-
-        // for &j in js {
-        //     println!("{:?}", relations[j].0.keys());
-        // }
-
-        let mut intersection: Vec<Value> = relations[j_min].0.keys().cloned().collect();
-
-        for &j in js {
-            if j != j_min {
-                let rj = &relations[j].0;
-                intersection.retain(|t| rj.contains_key(t));
-            }
-        }
-
-        // println!("intersection of {:?}: {:?}", x, intersection);
-
-        let empty = Trie::default();
-
+        let depth = tuple.len();
         let mut tuple = tuple.to_vec();
-        for val in intersection {
-            let relations: Vec<_> = relations
-                .iter()
-                .zip(&self.query.atoms)
-                .map(|(r, a)| {
-                    if a.has_var(x) {
-                        r.0.get(&val).unwrap_or(&empty)
-                    } else {
-                        r
-                    }
-                })
-                .collect();
+        for val in self.intersect(depth, relations) {
+            let relations = self.descend(depth, relations, val);
             tuple.push(val);
-            self.gj(f, &tuple, &relations);
+            if !self.is_negated(negated, &tuple) {
+                self.gj(f, &tuple, &relations, negated)?;
+            }
             tuple.pop();
         }
+        ControlFlow::Continue(())
     }
 }
@@ -0,0 +1,30 @@
+use crate::ast::{Aggregate, Value};
+
+/// A join semilattice over the values of a column. Relations with a lattice
+/// keep one tuple for each key, whose last column is the join of every
+/// value inserted for that key.
+pub trait Lattice: Send + Sync + 'static {
+    /// The least upper bound of two values. It has to be associative,
+    /// commutative, and idempotent.
+    fn join(&self, a: Value, b: Value) -> Value;
+}
+
+/// Keeps the smallest value, compared as a signed integer.
+#[derive(Debug, Clone, Copy)]
+pub struct Min;
+
+impl Lattice for Min {
+    fn join(&self, a: Value, b: Value) -> Value {
+        Aggregate::Min.apply(&[a, b])
+    }
+}
+
+/// Keeps the largest value, compared as a signed integer.
+#[derive(Debug, Clone, Copy)]
+pub struct Max;
+
+impl Lattice for Max {
+    fn join(&self, a: Value, b: Value) -> Value {
+        Aggregate::Max.apply(&[a, b])
+    }
+}
@@ -1,49 +1,579 @@
 mod gj;
+mod lattice;
+mod persist;
+mod planner;
+mod rows;
+mod stats;
+mod trie;
+mod union_find;
 
 #[cfg(test)]
 mod tests;
 
-use std::borrow::BorrowMut;
+use std::{
+    borrow::BorrowMut,
+    ops::{ControlFlow, Range, RangeBounds},
+    sync::Arc,
+};
 
 use crate::ast::*;
+use crate::transaction::Transactional;
 use crate::util::*;
 
+pub use crate::transaction::Transaction;
 pub use gj::CompiledQuery;
+pub use lattice::{Lattice, Max, Min};
+use rows::Rows;
+use stats::ColumnSketch;
+pub use stats::{ColumnStats, RelationStats};
+use trie::Trie;
+use union_find::UnionFind;
+
+/// Which tuples of a relation a query atom reads.
+///
+/// Tuples are kept in insertion order, so the recent tuples of a relation
+/// are a range of rows (see [`Deltas`]). `Stable` reads the rows before that
+/// range, `Recent` reads the range itself, and `All` reads both.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Version {
+    Stable,
+    Recent,
+    All,
+}
+
+/// For each relation, the range of rows that are recent. Relations that are
+/// not listed have no recent rows.
+pub type Deltas = IndexMap<Symbol, Range<usize>>;
+
+/// For each relation, a set of tuples that `Recent` atoms read instead of a
+/// range of rows.
+pub type Tuples = IndexMap<Symbol, IndexSet<Vec<Value>>>;
 
 #[derive(Clone)]
 pub struct Relation {
-    // TODO shouldn't be pub
-    pub set: IndexSet<Vec<Value>>,
+    pub(crate) rows: Rows,
     pub arity: usize,
-    // schema: Vec<Type>,
+    /// The names and types of the columns, if the relation was declared
+    /// with them.
+    pub schema: Option<Schema>,
+    // tries over all the tuples, keyed by the order of their columns
+    indexes: IndexMap<Vec<usize>, Trie>,
+    // the indexes added for scans, which dropping a query doesn't remove
+    sorted: HashSet<Vec<usize>>,
+    key: Option<FunctionalDependency>,
+    // equivalence relations only store a tuple from each element to the
+    // representative of its class
+    union_find: Option<UnionFind>,
+    // tuples replaced by a merge, they stay until compacting
+    superseded: IndexSet<Vec<Value>>,
+    // distinct values and bounds of each column, they only grow until the
+    // relation is cleared or its empty rows are freed
+    columns: Vec<ColumnSketch>,
+    // how to undo the changes made since the database's transaction began
+    savepoint: Option<Savepoint>,
+}
+
+/// The state of a relation when a transaction began.
+#[derive(Clone)]
+enum Savepoint {
+    // only rows were appended since, so they can be cut off
+    Rows {
+        len: usize,
+        columns: Vec<ColumnSketch>,
+    },
+    // something else changed, so the relation was copied first
+    Copy(Box<Relation>),
+}
+
+/// Key columns that determine the other columns of a relation, whose values
+/// are elements of a lattice.
+#[derive(Clone)]
+struct FunctionalDependency {
+    key: Vec<usize>,
+    // the columns that are not in the key
+    values: Vec<usize>,
+    lattice: Arc<dyn Lattice>,
+    // the joined values of each key
+    joined: IndexMap<Vec<Value>, Vec<Value>>,
 }
 
 impl Relation {
     pub fn new(arity: usize) -> Relation {
         Self {
-            set: Default::default(),
+            rows: Rows::new(arity),
             arity,
+            schema: None,
+            indexes: Default::default(),
+            sorted: Default::default(),
+            key: None,
+            union_find: None,
+            superseded: Default::default(),
+            columns: vec![ColumnSketch::default(); arity],
+            savepoint: None,
+        }
+    }
+
+    /// A relation of the same kind, with the same schema and key, but
+    /// without tuples or indexes.
+    pub(crate) fn empty_copy(&self) -> Relation {
+        let mut rel = Relation::new(self.arity);
+        rel.schema = self.schema.clone();
+        rel.key = self.key.as_ref().map(|fd| FunctionalDependency {
+            key: fd.key.clone(),
+            values: fd.values.clone(),
+            lattice: fd.lattice.clone(),
+            joined: Default::default(),
+        });
+        if self.union_find.is_some() {
+            rel.union_find = Some(UnionFind::default());
+        }
+        rel
+    }
+
+    /// A copy of the tuples without the indexes, so the queries that read it
+    /// only build the ones they use. It is not part of any transaction.
+    pub(crate) fn copy_tuples(&self) -> Relation {
+        Relation {
+            rows: self.rows.clone(),
+            key: self.key.clone(),
+            union_find: self.union_find.clone(),
+            superseded: self.superseded.clone(),
+            columns: self.columns.clone(),
+            ..self.empty_copy()
+        }
+    }
+
+    /// Makes the last column hold elements of a lattice, so the other
+    /// columns are a key, see [`set_key`](Self::set_key).
+    pub fn set_lattice(&mut self, lattice: impl Lattice) {
+        assert!(self.arity > 0, "Lattice relations need a column");
+        let key: Vec<usize> = (0..self.arity - 1).collect();
+        self.set_key(&key, lattice)
+    }
+
+    /// Makes the given columns determine the others, whose values are
+    /// elements of a lattice, so there is only one tuple for each key.
+    /// Inserting a tuple whose key is already present joins the two values
+    /// column by column, and the old tuple is superseded by one with the
+    /// joined values, unless they are the old values. Superseded tuples stay
+    /// until [`compact`](Self::compact).
+    pub fn set_key(&mut self, key: &[usize], merge: impl Lattice) {
+        self.modify();
+        assert!(
+            key.iter().all(|&i| i < self.arity),
+            "Key column out of range"
+        );
+        let values: Vec<usize> = (0..self.arity).filter(|i| !key.contains(i)).collect();
+        assert!(!values.is_empty(), "Every column is in the key");
+        let tuples = std::mem::replace(&mut self.rows, Rows::new(self.arity));
+        self.clear();
+        self.key = Some(FunctionalDependency {
+            key: key.to_vec(),
+            values,
+            lattice: Arc::new(merge),
+            joined: Default::default(),
+        });
+        self.reinsert(tuples);
+    }
+
+    /// Makes this an equivalence relation. Inserting a tuple merges the
+    /// classes of its two elements, but only a tuple from each element to
+    /// the representative of its class is stored, so queries have to join
+    /// two of those to see every pair of equivalent elements. Tuples of
+    /// elements that moved to another class are superseded and stay until
+    /// [`compact`](Self::compact).
+    pub fn set_equivalence(&mut self) {
+        self.modify();
+        assert_eq!(self.arity, 2, "Equivalence relations need two columns");
+        assert!(self.key.is_none(), "Equivalence relations can't have a key");
+        let tuples = std::mem::replace(&mut self.rows, Rows::new(self.arity));
+        self.clear();
+        self.union_find = Some(UnionFind::default());
+        self.reinsert(tuples);
+    }
+
+    // inserts tuples that were taken out before the relation changed kind
+    fn reinsert(&mut self, tuples: Rows) {
+        for tuple in tuples.iter() {
+            self.insert(tuple);
+        }
+        self.compact();
+    }
+
+    pub fn is_equivalence(&self) -> bool {
+        self.union_find.is_some()
+    }
+
+    /// Whether inserting a tuple can supersede another one.
+    pub fn merges(&self) -> bool {
+        self.key.is_some() || self.union_find.is_some()
+    }
+
+    /// Removes the tuples superseded by merges, returns the rows that were
+    /// freed, like [`remove`](Self::remove).
+    pub fn compact(&mut self) -> Vec<usize> {
+        if self.superseded.is_empty() {
+            return vec![];
+        }
+        let superseded = self.superseded.clone();
+        self.remove(&superseded)
+    }
+
+    /// The tuples that [`compact`](Self::compact) would remove.
+    pub fn superseded(&self) -> impl Iterator<Item = &[Value]> + '_ {
+        self.superseded.iter().map(|tuple| tuple.as_slice())
+    }
+
+    /// Whether the relation holds a tuple. For equivalence relations, that
+    /// is whether the two elements are in the same class.
+    pub fn contains(&self, tuple: &[Value]) -> bool {
+        match &self.union_find {
+            Some(uf) => uf.find(tuple[0]).is_some() && uf.find(tuple[0]) == uf.find(tuple[1]),
+            None => self.rows.contains(tuple),
+        }
+    }
+
+    /// Calls `f` on every tuple. For equivalence relations, that is every
+    /// pair of elements in the same class rather than the stored tuples.
+    pub fn for_each(&self, mut f: impl FnMut(&[Value])) {
+        match &self.union_find {
+            Some(uf) => {
+                for class in uf.classes() {
+                    for &a in class {
+                        for &b in class {
+                            f(&[a, b])
+                        }
+                    }
+                }
+            }
+            None => self.rows.iter().for_each(f),
         }
     }
 
+    /// Builds an index with the columns in the given order, unless there
+    /// already is one. Indexes are kept up to date as tuples are inserted.
+    pub(crate) fn add_index(&mut self, shuffle: &[usize]) {
+        if self.indexes.contains_key(shuffle) {
+            return;
+        }
+        let mut trie = Trie::default();
+        for (row, tuple) in self.rows.range(0..self.rows.len()) {
+            trie.insert(shuffle, tuple, row);
+        }
+        self.indexes.insert(shuffle.to_vec(), trie);
+    }
+
+    pub(crate) fn index(&self, shuffle: &[usize]) -> Option<&Trie> {
+        self.indexes.get(shuffle)
+    }
+
+    // the given columns followed by the others, in order
+    fn sorted_order(&self, columns: &[usize]) -> Vec<usize> {
+        let rest = (0..self.arity).filter(|i| !columns.contains(i));
+        columns.iter().copied().chain(rest).collect()
+    }
+
+    /// Builds an index sorted by the given columns, then the others, for
+    /// [`scan`](Self::scan). Queries share it when they read the columns in
+    /// that order.
+    pub fn add_sorted_index(&mut self, columns: &[usize]) {
+        assert!(
+            columns.iter().all(|&i| i < self.arity),
+            "Index column out of range"
+        );
+        let order = self.sorted_order(columns);
+        self.add_index(&order);
+        self.sorted.insert(order);
+    }
+
+    /// Calls `f` on the tuples whose first `columns` hold the values in
+    /// `prefix`, and whose next column is in `range`, sorted by those
+    /// columns. Values are compared as signed integers. Superseded tuples
+    /// are scanned until they are compacted away.
+    ///
+    /// Panics unless [`add_sorted_index`](Self::add_sorted_index) was
+    /// called with the same columns.
+    pub fn scan(
+        &self,
+        columns: &[usize],
+        prefix: &[Value],
+        range: impl RangeBounds<Value>,
+        mut f: impl FnMut(&[Value]),
+    ) {
+        assert!(
+            prefix.len() < columns.len(),
+            "Nothing to scan after the prefix"
+        );
+        let order = self.sorted_order(columns);
+        let index = self
+            .index(&order)
+            .expect("No sorted index on these columns");
+        let Some(trie) = index.view(self.rows.len()).get_path(prefix) else {
+            return;
+        };
+        // the columns below the scanned one
+        let depth = self.arity - prefix.len() - 1;
+        for (_, child) in trie.range(range) {
+            child.for_each_row(depth, &mut |row| f(self.rows.get(row)));
+        }
+    }
+
+    /// An estimate of the number of distinct values in a column.
+    pub fn distinct_values(&self, column: usize) -> usize {
+        self.columns[column].stats().distinct
+    }
+
+    /// The number of tuples, and the estimated distinct values and the
+    /// bounds of each column. Superseded and removed tuples count towards
+    /// the columns until the rows they leave empty are freed, see
+    /// [`remove`](Self::remove).
+    pub fn stats(&self) -> RelationStats {
+        let mut columns: Vec<ColumnStats> = self.columns.iter().map(ColumnSketch::stats).collect();
+        // every element has a stored tuple to its representative, and the
+        // pairs of equivalent elements go both ways
+        if self.is_equivalence() {
+            columns[1] = columns[0].clone();
+        }
+        RelationStats {
+            len: self.len(),
+            columns,
+        }
+    }
+
+    /// Removes every tuple, keeping the indexes around but empty.
+    pub fn clear(&mut self) {
+        self.modify();
+        self.rows.clear();
+        self.superseded.clear();
+        self.columns.fill(ColumnSketch::default());
+        if let Some(fd) = &mut self.key {
+            fd.joined.clear();
+        }
+        if let Some(uf) = &mut self.union_find {
+            uf.clear();
+        }
+        for trie in self.indexes.values_mut() {
+            *trie = Trie::default();
+        }
+    }
+
+    /// Removes the given tuples, and the rows of the others stay as they
+    /// are: the tuples are pruned from the indexes, and their rows are left
+    /// empty. Once there are as many empty rows as tuples, the empty rows
+    /// are freed, which moves each row after them down by one for each, and
+    /// rebuilds the indexes. Returns the rows that were freed.
+    pub fn remove(&mut self, tuples: &IndexSet<Vec<Value>>) -> Vec<usize> {
+        self.modify();
+        if let Some(fd) = &mut self.key {
+            for tuple in tuples {
+                let (key, values) = fd.split(tuple);
+                if fd.joined.get(&key) == Some(&values) {
+                    fd.joined.swap_remove(&key);
+                }
+            }
+        }
+        // the classes only change when current tuples are removed
+        let mut current = false;
+        for tuple in tuples {
+            let Some(row) = self.rows.get_index_of(tuple) else {
+                continue;
+            };
+            for (shuffle, trie) in &mut self.indexes {
+                trie.remove(shuffle, tuple, row);
+            }
+            self.rows.remove(row);
+            current |= !self.superseded.swap_remove(tuple);
+        }
+        if let (Some(uf), true) = (&mut self.union_find, current) {
+            let superseded = &self.superseded;
+            let current = self.rows.iter().filter(|t| !superseded.contains(*t));
+            uf.rebuild(current);
+        }
+        if self.rows.removed() * 2 < self.rows.len() {
+            return vec![];
+        }
+        self.free_removed_rows()
+    }
+
+    // drops the empty rows, so the indexes and column sketches are rebuilt
+    fn free_removed_rows(&mut self) -> Vec<usize> {
+        let freed = (0..self.rows.len())
+            .filter(|&row| self.rows.is_removed(row))
+            .collect();
+        self.rows.retain(|_| true);
+        self.columns.fill(ColumnSketch::default());
+        for tuple in self.rows.iter() {
+            for (column, &value) in self.columns.iter_mut().zip(tuple) {
+                column.insert(value);
+            }
+        }
+        for (shuffle, trie) in &mut self.indexes {
+            *trie = Trie::default();
+            for (row, tuple) in self.rows.iter().enumerate() {
+                trie.insert(shuffle, tuple, row);
+            }
+        }
+        freed
+    }
+
+    /// The stored tuples in the order they were inserted, superseded ones
+    /// included until compacting. Equivalence relations store a tuple from
+    /// each element to the representative of its class, use
+    /// [`for_each`](Self::for_each) for every pair.
+    pub fn iter(&self) -> impl Iterator<Item = &[Value]> + '_ {
+        self.rows.iter()
+    }
+
+    /// The tuples in a range of rows, in order.
+    pub fn rows(&self, range: Range<usize>) -> impl Iterator<Item = &[Value]> + '_ {
+        self.rows.range(range).map(|(_, tuple)| tuple)
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.set.is_empty()
+        self.rows.is_empty()
     }
 
+    /// The number of tuples, not counting superseded ones. For equivalence
+    /// relations, that is the number of pairs of equivalent elements, which
+    /// [`for_each`](Self::for_each) goes through.
     pub fn len(&self) -> usize {
-        let n = self.set.len();
-        n.checked_div(self.arity).unwrap_or(n)
+        match &self.union_find {
+            Some(uf) => uf.pairs(),
+            None => self.stored_len(),
+        }
+    }
+
+    // the number of tuples in the rows that are not superseded
+    pub(crate) fn stored_len(&self) -> usize {
+        self.rows.len() - self.rows.removed() - self.superseded.len()
     }
 
-    pub fn insert(&mut self, tuple: &[Value]) {
+    pub fn insert(&mut self, tuple: &[Value]) -> bool {
         assert_eq!(tuple.len(), self.arity);
-        self.set.insert(tuple.to_vec());
+        // merging changes more than the rows
+        if self.merges() {
+            self.modify();
+        }
+        if let Some(uf) = &mut self.union_find {
+            let moved = uf.union(tuple[0], tuple[1]);
+            let tuples: Vec<[Value; 2]> = moved
+                .iter()
+                .map(|&(x, _old)| [x, uf.find(x).unwrap()])
+                .collect();
+            for &(x, old) in &moved {
+                if let Some(old) = old {
+                    self.superseded.insert(vec![x, old]);
+                }
+            }
+            for tuple in &tuples {
+                self.insert_row(tuple);
+            }
+            return !moved.is_empty();
+        }
+
+        let mut merged = vec![];
+        let tuple = match &mut self.key {
+            None => tuple,
+            Some(fd) => {
+                let (key, values) = fd.split(tuple);
+                match fd.joined.get_mut(&key) {
+                    None => {
+                        fd.joined.insert(key, values);
+                        tuple
+                    }
+                    Some(old) => {
+                        let lattice = &fd.lattice;
+                        let new: Vec<Value> = old
+                            .iter()
+                            .zip(&values)
+                            .map(|(&a, &b)| lattice.join(a, b))
+                            .collect();
+                        if new == *old {
+                            return false;
+                        }
+                        merged = tuple.to_vec();
+                        for (&i, &val) in fd.values.iter().zip(old.iter()) {
+                            merged[i] = val;
+                        }
+                        self.superseded.insert(merged.clone());
+                        for (&i, &val) in fd.values.iter().zip(&new) {
+                            merged[i] = val;
+                        }
+                        *old = new;
+                        &merged
+                    }
+                }
+            }
+        };
+        self.insert_row(tuple)
+    }
+
+    /// Remembers the state to go back to if the transaction that just
+    /// began is rolled back.
+    pub(crate) fn savepoint(&mut self) {
+        assert!(self.savepoint.is_none(), "A transaction is already open");
+        self.savepoint = Some(Savepoint::Rows {
+            len: self.rows.len(),
+            columns: self.columns.clone(),
+        });
+    }
+
+    // copies the relation as it was when the transaction began, before
+    // something other than appending rows changes it
+    fn modify(&mut self) {
+        if let Some(Savepoint::Rows { len, columns }) = &self.savepoint {
+            let (len, columns) = (*len, columns.clone());
+            let mut copy = self.clone();
+            copy.savepoint = None;
+            copy.truncate(len, columns);
+            self.savepoint = Some(Savepoint::Copy(Box::new(copy)));
+        }
+    }
+
+    // removes the rows from `len` on, which were only appended
+    fn truncate(&mut self, len: usize, columns: Vec<ColumnSketch>) {
+        let Self { rows, indexes, .. } = self;
+        for (shuffle, trie) in indexes {
+            for row in len..rows.len() {
+                trie.prune(shuffle, rows.get(row), len);
+            }
+        }
+        self.rows.truncate(len);
+        self.columns = columns;
+    }
+
+    /// Keeps the changes made since the transaction began.
+    pub(crate) fn release(&mut self) {
+        self.savepoint = None;
+    }
+
+    /// Undoes the changes made since the transaction began.
+    pub(crate) fn restore(&mut self) {
+        match self.savepoint.take() {
+            Some(Savepoint::Rows { len, columns }) => self.truncate(len, columns),
+            Some(Savepoint::Copy(relation)) => *self = *relation,
+            None => (),
+        }
+    }
+
+    // adds a tuple to the set and the indexes
+    fn insert_row(&mut self, tuple: &[Value]) -> bool {
+        let (row, is_new) = self.rows.insert_full(tuple);
+        if is_new {
+            for (column, &value) in self.columns.iter_mut().zip(tuple) {
+                column.insert(value);
+            }
+            for (shuffle, trie) in &mut self.indexes {
+                trie.insert(shuffle, tuple, row);
+            }
+        }
+        is_new
     }
 
     pub fn insert_many(&mut self, tuples: &[Value]) {
         assert_eq!(tuples.len() % self.arity, 0);
         for tuple in tuples.chunks_exact(self.arity) {
-            self.set.insert(tuple.to_vec());
+            self.insert(tuple);
         }
     }
 
@@ -64,11 +594,52 @@ impl Relation {
     }
 }
 
+impl FunctionalDependency {
+    // the values of the key columns and of the other columns
+    fn split(&self, tuple: &[Value]) -> (Vec<Value>, Vec<Value>) {
+        let key = self.key.iter().map(|&i| tuple[i]).collect();
+        let values = self.values.iter().map(|&i| tuple[i]).collect();
+        (key, values)
+    }
+}
+
 #[derive(Default)]
 pub struct Database {
     pub relations: IndexMap<Symbol, Relation>,
     queries: IndexMap<QueryHandle, CompiledQuery>,
     query_id: usize,
+    savepoint: Option<DatabaseSavepoint>,
+}
+
+// what a database had when a transaction began, the relations remember
+// their own state
+struct DatabaseSavepoint {
+    relations: usize,
+    queries: IndexMap<QueryHandle, CompiledQuery>,
+    query_id: usize,
+    // the indexes that queries stopped using since, which the queries being
+    // rolled back to may still read, so they are only dropped on commit
+    unused: Vec<(Symbol, Vec<usize>)>,
+}
+
+impl Transactional for Database {
+    fn release(&mut self) {
+        let savepoint = self.savepoint.take().expect("No transaction is open");
+        for rel in self.relations.values_mut() {
+            rel.release();
+        }
+        self.drop_unused_indexes(savepoint.unused);
+    }
+
+    fn restore(&mut self) {
+        let savepoint = self.savepoint.take().expect("No transaction is open");
+        self.relations.truncate(savepoint.relations);
+        for rel in self.relations.values_mut() {
+            rel.restore();
+        }
+        self.queries = savepoint.queries;
+        self.query_id = savepoint.query_id;
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
@@ -79,16 +650,45 @@ impl Database {
         self.relations
             .entry(symbol)
             .and_modify(|_| panic!("a relation was already here"))
-            .or_insert(Relation {
-                set: Default::default(),
-                arity,
-            })
+            .or_insert_with(|| Relation::new(arity))
     }
 }
 
 impl Database {
+    /// Begins a transaction. Until it is committed, every change to the
+    /// relations can be rolled back, including new relations and queries,
+    /// and so is dropping it. Relations that only had tuples appended are
+    /// cut back to their old length, the others are copied the first time
+    /// something else changes them, so a transaction only costs as much as
+    /// what it touches.
+    pub fn begin(&mut self) -> Transaction<'_, Self> {
+        self.savepoint();
+        Transaction::new(self)
+    }
+
+    pub(crate) fn savepoint(&mut self) {
+        assert!(self.savepoint.is_none(), "A transaction is already open");
+        self.savepoint = Some(DatabaseSavepoint {
+            relations: self.relations.len(),
+            queries: self.queries.clone(),
+            query_id: self.query_id,
+            unused: vec![],
+        });
+        for rel in self.relations.values_mut() {
+            rel.savepoint();
+        }
+    }
+
     pub fn add_query(&mut self, query: Query) -> QueryHandle {
-        let cq = CompiledQuery::new(self, query);
+        let versions = vec![Version::All; query.atoms.len()];
+        self.add_versioned_query(query, versions)
+    }
+
+    /// Adds a query where each atom only reads the given [`Version`] of its
+    /// relation.
+    pub fn add_versioned_query(&mut self, query: Query, versions: Vec<Version>) -> QueryHandle {
+        let cq = CompiledQuery::new(self, query, versions);
+        self.add_indexes(&cq);
         let handle = QueryHandle(self.query_id);
         self.query_id += 1;
         let old = self.queries.insert(handle, cq);
@@ -96,12 +696,120 @@ impl Database {
         handle
     }
 
+    /// The statistics of every relation.
+    pub fn stats(&self) -> IndexMap<Symbol, RelationStats> {
+        let relations = self.relations.iter();
+        relations.map(|(&sym, rel)| (sym, rel.stats())).collect()
+    }
+
+    /// Drops a query, along with the indexes no other query uses.
+    pub fn remove_query(&mut self, handle: QueryHandle) {
+        let cq = self.queries.shift_remove(&handle).expect("No such query");
+        self.remove_unused_indexes(&cq);
+    }
+
+    // drops the indexes of a query that was removed or replanned that no
+    // query uses any more, unless they were added for scans or a
+    // transaction is open
+    fn remove_unused_indexes(&mut self, old: &CompiledQuery) {
+        let indexes = old.indexes().map(|(sym, shuffle)| (sym, shuffle.to_vec()));
+        match &mut self.savepoint {
+            Some(savepoint) => savepoint.unused.extend(indexes),
+            None => {
+                let indexes = indexes.collect();
+                self.drop_unused_indexes(indexes);
+            }
+        }
+    }
+
+    fn drop_unused_indexes(&mut self, indexes: Vec<(Symbol, Vec<usize>)>) {
+        let in_use: HashSet<(Symbol, &[usize])> =
+            self.queries.values().flat_map(|q| q.indexes()).collect();
+        let unused: Vec<(Symbol, Vec<usize>)> = indexes
+            .into_iter()
+            .filter(|(sym, shuffle)| !in_use.contains(&(*sym, shuffle.as_slice())))
+            .collect();
+        for (sym, shuffle) in unused {
+            let rel = &mut self.relations[&sym];
+            if !rel.sorted.contains(&shuffle) {
+                rel.indexes.shift_remove(&shuffle);
+            }
+        }
+    }
+
+    fn add_indexes(&mut self, cq: &CompiledQuery) {
+        for (sym, shuffle) in cq.indexes() {
+            let rel = self.relations.get_mut(&sym);
+            let rel = rel.unwrap_or_else(|| panic!("No relation {}", sym));
+            rel.add_index(shuffle);
+        }
+    }
+
+    /// Reorders the variables of a query if the relations it reads changed
+    /// size a lot since it was last planned. Returns true if it was replanned.
+    pub fn refresh_plan(&mut self, handle: QueryHandle, deltas: &Deltas) -> bool {
+        if !self.queries[&handle].needs_replan(self) {
+            return false;
+        }
+        let mut cq = self.queries[&handle].clone();
+        cq.plan(self, deltas);
+        self.add_indexes(&cq);
+        let old = std::mem::replace(&mut self.queries[&handle], cq);
+        self.remove_unused_indexes(&old);
+        true
+    }
+
     pub fn eval_query<F>(&self, handle: QueryHandle, f: F)
     where
         F: FnMut(&[Value]),
+    {
+        self.eval_query_with_deltas(handle, &Deltas::default(), f)
+    }
+
+    pub fn eval_query_with_deltas<F>(&self, handle: QueryHandle, deltas: &Deltas, f: F)
+    where
+        F: FnMut(&[Value]),
+    {
+        let query = &self.queries[&handle];
+        query.eval(self, deltas, f)
+    }
+
+    /// Evaluates a query where the `Recent` atoms read the given tuples
+    /// instead of a range of rows, and the other atoms read every row.
+    pub fn eval_query_with_tuples<F>(&self, handle: QueryHandle, tuples: &Tuples, f: F)
+    where
+        F: FnMut(&[Value]),
+    {
+        let query = &self.queries[&handle];
+        query.eval_with_tuples(self, tuples, f)
+    }
+
+    /// Evaluates a query across rayon's worker threads, see
+    /// [`CompiledQuery::par_eval`].
+    pub fn par_eval_query<T, F>(&self, handle: QueryHandle, deltas: &Deltas, f: F) -> Vec<T>
+    where
+        T: Default + Send,
+        F: Fn(&mut T, &[Value]) + Sync,
+    {
+        let query = &self.queries[&handle];
+        query.par_eval(self, deltas, f)
+    }
+
+    /// Evaluates a query across rayon's worker threads until `f` breaks,
+    /// see [`CompiledQuery::try_par_eval`].
+    pub fn try_par_eval_query<T, B, F>(
+        &self,
+        handle: QueryHandle,
+        deltas: &Deltas,
+        f: F,
+    ) -> ControlFlow<B, Vec<T>>
+    where
+        T: Default + Send,
+        B: Send,
+        F: Fn(&mut T, &[Value]) -> ControlFlow<B> + Sync,
     {
         let query = &self.queries[&handle];
-        query.eval(self, f)
+        query.try_par_eval(self, deltas, f)
     }
 
     pub fn get_indexes(&self, handle: QueryHandle, vars: &[Symbol]) -> Vec<usize> {
@@ -125,7 +833,9 @@ impl Database {
     {
         let v = vec.borrow_mut();
         let query = &self.queries[&handle];
-        query.eval(self, |values| v.extend_from_slice(values));
+        query.eval(self, &Deltas::default(), |values| {
+            v.extend_from_slice(values)
+        });
         vec
     }
 }
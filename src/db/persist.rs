@@ -0,0 +1,240 @@
+//! The file format of [`Database::save`]. All integers are little-endian.
+//!
+//! ```text
+//! file      = magic version count:u32 relation* checksum
+//! magic     = "DSTK"
+//! version   = u32
+//! relation  = name:str arity:u32 schema tuples:u64 value:u64*
+//! schema    = 0:u8 | 1:u8 (column:str type:str)*
+//! str       = len:u32 utf8
+//! checksum  = crc32 of everything before it:u32
+//! ```
+//!
+//! Each relation has `arity * tuples` values, and a schema has `arity`
+//! columns.
+
+use std::any::TypeId;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::{Database, Relation};
+use crate::ast::{Schema, Type, Value};
+use crate::parse::TypeParser;
+use crate::util::Symbol;
+use crate::Error;
+
+const MAGIC: &[u8; 4] = b"DSTK";
+const VERSION: u32 = 1;
+
+impl Database {
+    /// Writes the name, arity, schema, and tuples of every relation to a
+    /// file. Superseded tuples are left out, and so are keys, lattices, and
+    /// equivalence, since a lattice can be any type: set them again after
+    /// [`load`](Self::load), which merges the loaded tuples the same way.
+    ///
+    /// The database is written to a temporary file next to `path`, which
+    /// then replaces it, so a crash while saving leaves either the old file
+    /// or the new one.
+    ///
+    /// Fails if the file can't be written, or if a schema has a type that
+    /// can't be named in a program.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut name = path
+            .file_name()
+            .ok_or_else(|| Error::Io(format!("{} is not a file name", path.display())))?
+            .to_os_string();
+        name.push(format!(".{}.tmp", std::process::id()));
+        let temp = path.with_file_name(name);
+        let written = File::create(&temp)
+            .map_err(io_error)
+            .and_then(|file| self.write(file))
+            .and_then(|()| std::fs::rename(&temp, path).map_err(io_error));
+        if written.is_err() {
+            // the temporary file may not exist, and the error to report is
+            // the one that stopped the save
+            let _ = std::fs::remove_file(&temp);
+        }
+        written
+    }
+
+    // writes the whole database to a file and syncs it to disk
+    fn write(&self, file: File) -> Result<(), Error> {
+        let mut writer = Writer {
+            inner: BufWriter::new(file),
+            hasher: crc32fast::Hasher::new(),
+        };
+        writer.write(MAGIC)?;
+        writer.u32(VERSION)?;
+        writer.u32(self.relations.len().try_into().unwrap())?;
+        for (&symbol, rel) in &self.relations {
+            writer.str(symbol.as_ref())?;
+            writer.u32(rel.arity.try_into().unwrap())?;
+            match &rel.schema {
+                Some(schema) => {
+                    writer.write(&[1])?;
+                    for &(column, ty) in schema.columns() {
+                        let ty = type_name(ty).ok_or(Error::UnsupportedType {
+                            relation: symbol,
+                            column,
+                        })?;
+                        writer.str(column.as_ref())?;
+                        writer.str(ty)?;
+                    }
+                }
+                None => writer.write(&[0])?,
+            }
+            writer.u64(rel.stored_len() as u64)?;
+            for tuple in rel.rows.iter() {
+                if rel.superseded.contains(tuple) {
+                    continue;
+                }
+                for value in tuple {
+                    writer.u64(value.to_bits())?;
+                }
+            }
+        }
+        let checksum = writer.hasher.clone().finalize();
+        writer.u32(checksum)?;
+        let file = writer
+            .inner
+            .into_inner()
+            .map_err(|e| io_error(e.into_error()))?;
+        file.sync_all().map_err(io_error)
+    }
+
+    /// Reads a database written by [`save`](Self::save).
+    ///
+    /// Fails if the file can't be read, was written by another version of
+    /// the format, or doesn't match its checksum.
+    pub fn load(path: impl AsRef<Path>) -> Result<Database, Error> {
+        let bytes = std::fs::read(path).map_err(io_error)?;
+        if bytes.len() < MAGIC.len() + 8 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(corrupt("not a database file"));
+        }
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(corrupt("the checksum doesn't match"));
+        }
+
+        let mut reader = Reader { bytes: &body[8..] };
+        let mut db = Database::default();
+        for _ in 0..reader.u32()? {
+            let symbol = Symbol::new(reader.str()?);
+            if db.relations.contains_key(&symbol) {
+                return Err(corrupt("a relation appears twice"));
+            }
+            let arity = reader.u32()? as usize;
+            let schema = match reader.take(1)? {
+                [0] => None,
+                [1] => {
+                    let mut columns = Vec::with_capacity(arity);
+                    for _ in 0..arity {
+                        let column = Symbol::new(reader.str()?);
+                        let ty = TypeParser::new()
+                            .parse(reader.str()?)
+                            .map_err(|_| corrupt("a column has an unknown type"))?;
+                        columns.push((column, ty));
+                    }
+                    Some(Schema::from_named_types(columns))
+                }
+                _ => return Err(corrupt("a schema flag is neither 0 nor 1")),
+            };
+            let mut rel = Relation::new(arity);
+            rel.schema = schema;
+            let len: Option<usize> = reader.u64()?.try_into().ok();
+            let size = len.and_then(|len| len.checked_mul(arity * 8));
+            let values = reader.take(size.ok_or_else(|| corrupt("too many tuples"))?)?;
+            let values: Vec<Value> = values
+                .chunks_exact(8)
+                .map(|v| Value::from_bits(u64::from_le_bytes(v.try_into().unwrap())))
+                .collect();
+            if arity == 0 {
+                // tuples without columns have no values to read
+                if len > Some(0) {
+                    rel.insert(&[]);
+                }
+            } else {
+                rel.insert_many(&values);
+            }
+            db.relations.insert(symbol, rel);
+        }
+        if !reader.bytes.is_empty() {
+            return Err(corrupt("there are bytes after the last relation"));
+        }
+        Ok(db)
+    }
+}
+
+// the name of a type in programs
+fn type_name(ty: TypeId) -> Option<&'static str> {
+    (ty == i32::type_id()).then_some("i32")
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::Io(e.to_string())
+}
+
+fn corrupt(reason: &str) -> Error {
+    Error::CorruptFile(reason.to_string())
+}
+
+// checksums everything it writes
+struct Writer<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Writer<W> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.hasher.update(bytes);
+        self.inner.write_all(bytes).map_err(io_error)
+    }
+
+    fn u32(&mut self, n: u32) -> Result<(), Error> {
+        self.write(&n.to_le_bytes())
+    }
+
+    fn u64(&mut self, n: u64) -> Result<(), Error> {
+        self.write(&n.to_le_bytes())
+    }
+
+    fn str(&mut self, s: &str) -> Result<(), Error> {
+        self.u32(s.len().try_into().unwrap())?;
+        self.write(s.as_bytes())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if n > self.bytes.len() {
+            return Err(corrupt("the file ends in the middle of a relation"));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str, Error> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| corrupt("a name isn't UTF-8"))
+    }
+}
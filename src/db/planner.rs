@@ -0,0 +1,65 @@
+use super::*;
+
+use super::gj::VarOccurences;
+
+/// Orders the variables of a query for generic join. Variables are picked
+/// greedily, each time taking the one with the fewest estimated candidate
+/// values given the variables already bound, so the join narrows down as
+/// early as possible.
+///
+/// The size of a recent atom is the size of its delta, everything else is
+/// estimated from the relation's cardinality and distinct values per column.
+pub(crate) fn order_vars(
+    db: &Database,
+    query: &Query,
+    sizes: &[usize],
+    by_var: VarOccurences,
+) -> VarOccurences {
+    // distinct values for each column of each atom, capped by its size
+    let distinct: Vec<Vec<usize>> = query
+        .atoms
+        .iter()
+        .zip(sizes)
+        .map(|(atom, &size)| {
+            let rel = &db.relations[&atom.relation];
+            (0..atom.terms.len())
+                .map(|col| rel.distinct_values(col).min(size).max(1))
+                .collect()
+        })
+        .collect();
+
+    let estimate = |bound: &[Variable], var: Variable| {
+        by_var[&var]
+            .iter()
+            .map(|&i| {
+                let atom = &query.atoms[i];
+                let mut size = sizes[i].max(1) as f64;
+                let mut var_distinct = f64::INFINITY;
+                for (col, term) in atom.terms.iter().enumerate() {
+                    let d = distinct[i][col] as f64;
+                    match term {
+                        Term::Variable(v) if *v == var => var_distinct = var_distinct.min(d),
+                        Term::Variable(v) if !bound.contains(v) => (),
+                        _ => size /= d,
+                    }
+                }
+                var_distinct.min(size.max(1.0))
+            })
+            .fold(f64::INFINITY, f64::min)
+    };
+
+    let mut order: Vec<Variable> = vec![];
+    while order.len() < by_var.len() {
+        let next = by_var
+            .iter()
+            .filter(|(v, _)| !order.contains(v))
+            .map(|(&v, occ)| (estimate(&order, v), occ.len(), v))
+            // cheapest first, then the one in the most atoms, then the first
+            .min_by(|(e1, n1, _), (e2, n2, _)| e1.total_cmp(e2).then(n2.cmp(n1)))
+            .map(|(_, _, v)| v)
+            .unwrap();
+        order.push(next);
+    }
+
+    order.into_iter().map(|v| (v, by_var[&v].clone())).collect()
+}
@@ -0,0 +1,211 @@
+use std::{convert::TryFrom, hash::BuildHasher, ops::Range};
+
+use hashbrown::{DefaultHashBuilder, HashTable};
+
+use crate::ast::Value;
+
+/// Tuples of one arity stored back to back in a single buffer, in insertion
+/// order, so row `i` is `values[i * arity..(i + 1) * arity]`. A hash table
+/// of row ids finds the row of a tuple without storing the tuple again.
+/// Removed tuples leave their row empty, so the rows after them don't move.
+#[derive(Clone, Default)]
+pub(crate) struct Rows {
+    arity: usize,
+    values: Vec<Value>,
+    // tuples without columns have no values to count
+    len: usize,
+    ids: HashTable<u32>,
+    hasher: DefaultHashBuilder,
+    // which rows are empty, only as long as the last one of them
+    removed: Vec<bool>,
+    removed_len: usize,
+}
+
+impl Rows {
+    pub fn new(arity: usize) -> Self {
+        Self {
+            arity,
+            ..Default::default()
+        }
+    }
+
+    /// The number of rows, empty ones included.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The number of empty rows.
+    pub fn removed(&self) -> usize {
+        self.removed_len
+    }
+
+    pub fn is_removed(&self, row: usize) -> bool {
+        self.removed.get(row).copied().unwrap_or(false)
+    }
+
+    /// Whether there are no tuples, even if there are empty rows.
+    pub fn is_empty(&self) -> bool {
+        self.len == self.removed_len
+    }
+
+    pub fn get(&self, row: usize) -> &[Value] {
+        assert!(row < self.len, "Row {} out of range", row);
+        &self.values[row * self.arity..(row + 1) * self.arity]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[Value]> + '_ {
+        self.range(0..self.len).map(|(_, tuple)| tuple)
+    }
+
+    /// The tuples in a range of rows with their rows, skipping empty rows.
+    pub fn range(&self, range: Range<usize>) -> impl Iterator<Item = (usize, &[Value])> + '_ {
+        let end = range.end.min(self.len);
+        (range.start..end)
+            .filter(move |&row| !self.is_removed(row))
+            .map(move |row| (row, self.get(row)))
+    }
+
+    pub fn get_index_of(&self, tuple: &[Value]) -> Option<usize> {
+        let hash = self.hasher.hash_one(tuple);
+        let id = self.ids.find(hash, |&id| self.get(id as usize) == tuple)?;
+        Some(*id as usize)
+    }
+
+    pub fn contains(&self, tuple: &[Value]) -> bool {
+        self.get_index_of(tuple).is_some()
+    }
+
+    /// Appends a tuple unless it is already here. Returns its row, and
+    /// whether it is new.
+    pub fn insert_full(&mut self, tuple: &[Value]) -> (usize, bool) {
+        debug_assert_eq!(tuple.len(), self.arity);
+        if let Some(row) = self.get_index_of(tuple) {
+            return (row, false);
+        }
+        let row = self.len;
+        let id = u32::try_from(row).expect("Too many rows for one relation");
+        let Self {
+            arity,
+            values,
+            ids,
+            hasher,
+            ..
+        } = self;
+        let rehash = |&id: &u32| {
+            let row = id as usize * *arity;
+            hasher.hash_one(&values[row..row + *arity])
+        };
+        ids.insert_unique(hasher.hash_one(tuple), id, rehash);
+        self.values.extend_from_slice(tuple);
+        self.len += 1;
+        (row, true)
+    }
+
+    /// Removes the tuple in a row, leaving the row empty.
+    pub fn remove(&mut self, row: usize) {
+        assert!(!self.is_removed(row), "Row {} is already empty", row);
+        self.remove_id(row);
+        if self.removed.len() <= row {
+            self.removed.resize(row + 1, false);
+        }
+        self.removed[row] = true;
+        self.removed_len += 1;
+    }
+
+    fn remove_id(&mut self, row: usize) {
+        let hash = self.hasher.hash_one(self.get(row));
+        let entry = self.ids.find_entry(hash, |&id| id as usize == row);
+        entry.expect("Row is not in the table").remove();
+    }
+
+    /// Removes the rows from `len` on.
+    pub fn truncate(&mut self, len: usize) {
+        for row in len..self.len {
+            if !self.is_removed(row) {
+                self.remove_id(row);
+            }
+        }
+        if self.removed.len() > len {
+            self.removed_len -= self.removed[len..].iter().filter(|&&r| r).count();
+            self.removed.truncate(len);
+        }
+        self.values.truncate(len * self.arity);
+        self.len = self.len.min(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.ids.clear();
+        self.removed.clear();
+        self.removed_len = 0;
+        self.len = 0;
+    }
+
+    /// Keeps the tuples `f` returns true for, in order, without empty rows.
+    pub fn retain(&mut self, mut f: impl FnMut(&[Value]) -> bool) {
+        let old = std::mem::replace(self, Rows::new(self.arity));
+        self.values.reserve(old.values.len());
+        for tuple in old.iter().filter(|t| f(t)) {
+            self.insert_full(tuple);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Type;
+
+    #[test]
+    fn insert_and_retain() {
+        let tuple = |a: i32, b: i32| [a.to_value(), b.to_value()];
+        let mut rows = Rows::new(2);
+        assert_eq!(rows.insert_full(&tuple(1, 2)), (0, true));
+        assert_eq!(rows.insert_full(&tuple(2, 1)), (1, true));
+        assert_eq!(rows.insert_full(&tuple(1, 2)), (0, false));
+        assert_eq!(rows.insert_full(&tuple(3, 3)), (2, true));
+        assert_eq!(rows.get(1), tuple(2, 1));
+
+        rows.retain(|t| t[0] != 2.to_value());
+        let left: Vec<&[Value]> = rows.iter().collect();
+        assert_eq!(left, [&tuple(1, 2)[..], &tuple(3, 3)[..]]);
+        assert_eq!(rows.get_index_of(&tuple(3, 3)), Some(1));
+        assert!(!rows.contains(&tuple(2, 1)));
+
+        // a tuple without columns is either there or not
+        let mut unit = Rows::new(0);
+        assert!(unit.is_empty());
+        assert_eq!(unit.insert_full(&[]), (0, true));
+        assert_eq!(unit.insert_full(&[]), (0, false));
+        assert_eq!(unit.len(), 1);
+    }
+
+    #[test]
+    fn remove_and_truncate() {
+        let tuple = |a: i32| [a.to_value()];
+        let mut rows = Rows::new(1);
+        for i in 0..5 {
+            rows.insert_full(&tuple(i));
+        }
+        rows.remove(1);
+        rows.remove(3);
+        // the other rows stay where they were
+        let left: Vec<(usize, &[Value])> = rows.range(0..4).collect();
+        assert_eq!(left, [(0, &tuple(0)[..]), (2, &tuple(2)[..])]);
+        assert_eq!(rows.get_index_of(&tuple(4)), Some(4));
+        assert!(!rows.contains(&tuple(3)));
+        assert_eq!((rows.len(), rows.removed()), (5, 2));
+
+        // a removed tuple goes in a new row
+        assert_eq!(rows.insert_full(&tuple(1)), (5, true));
+        rows.truncate(3);
+        assert_eq!((rows.len(), rows.removed()), (3, 1));
+        assert!(!rows.contains(&tuple(1)));
+        rows.retain(|_| true);
+        assert_eq!(
+            rows.iter().collect::<Vec<_>>(),
+            [&tuple(0)[..], &tuple(2)[..]]
+        );
+        assert_eq!(rows.removed(), 0);
+    }
+}
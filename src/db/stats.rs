@@ -0,0 +1,96 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::ast::{Aggregate, Value};
+
+// 2^10 registers, which estimates within about 3% for 1KB per column
+const PRECISION: u32 = 10;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// A HyperLogLog sketch of the distinct values in a column. Values can be
+/// added but not removed.
+#[derive(Clone)]
+pub(crate) struct HyperLogLog {
+    registers: Box<[u8]>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; REGISTERS].into_boxed_slice(),
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn insert(&mut self, value: Value) {
+        // the default hasher has fixed keys, so estimates are reproducible
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let register = (hash >> (64 - PRECISION)) as usize;
+        // the position of the first one in the other bits
+        let rank = ((hash << PRECISION) | 1 << (PRECISION - 1)).leading_zeros() + 1;
+        let old = &mut self.registers[register];
+        *old = (*old).max(rank as u8);
+    }
+
+    pub fn estimate(&self) -> usize {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let empty = self.registers.iter().filter(|&&r| r == 0).count();
+        // small sets are counted better by how many registers are still empty
+        if estimate <= 2.5 * m && empty > 0 {
+            return (m * (m / empty as f64).ln()).round() as usize;
+        }
+        estimate.round() as usize
+    }
+}
+
+/// What a relation knows about one of its columns.
+#[derive(Clone, Default)]
+pub(crate) struct ColumnSketch {
+    distinct: HyperLogLog,
+    min: Option<Value>,
+    max: Option<Value>,
+}
+
+impl ColumnSketch {
+    pub fn insert(&mut self, value: Value) {
+        self.distinct.insert(value);
+        let join = |agg: Aggregate, old: Option<Value>| match old {
+            Some(old) => agg.apply(&[old, value]),
+            None => value,
+        };
+        self.min = Some(join(Aggregate::Min, self.min));
+        self.max = Some(join(Aggregate::Max, self.max));
+    }
+
+    pub fn stats(&self) -> ColumnStats {
+        ColumnStats {
+            distinct: self.distinct.estimate(),
+            min: self.min,
+            max: self.max,
+        }
+    }
+}
+
+/// Statistics of a relation, see [`Relation::stats`](super::Relation::stats).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelationStats {
+    /// The number of tuples.
+    pub len: usize,
+    pub columns: Vec<ColumnStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnStats {
+    /// An estimate of the number of distinct values.
+    pub distinct: usize,
+    /// The smallest and largest values, compared as signed integers, or
+    /// `None` if the relation never had a tuple.
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+}
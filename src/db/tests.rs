@@ -3,14 +3,20 @@ use std::convert::TryInto;
 use super::*;
 
 macro_rules! query {
-    ( $( $sym:ident ($($val:tt),*) ),+ ) => {
+    ( $( $sym:ident ($($val:tt),*) ),+ $(; $( ! $nsym:ident ($($nval:tt),*) ),+ )? ) => {
         Query {
             atoms: vec![$(
                 Atom {
                     relation: $sym,
                     terms: vec![$( value!($val)),*],
                 }
-            ),+]
+            ),+],
+            negated: vec![$($(
+                Atom {
+                    relation: $nsym,
+                    terms: vec![$( value!($nval)),*],
+                }
+            ),+)?],
         }
     };
 }
@@ -20,7 +26,7 @@ macro_rules! value {
         Term::Variable($ident)
     };
     ($val:expr) => {
-        Term::Value(Value($val))
+        Term::Value(<i32 as Type>::to_value($val))
     };
 }
 
@@ -32,10 +38,22 @@ impl Database {
         expected: &[[V; N]],
     ) where
         V: Type + Clone,
+    {
+        self.eval_and_check_with_deltas(handle, &Deltas::default(), vars, expected)
+    }
+
+    fn eval_and_check_with_deltas<V, const N: usize>(
+        &self,
+        handle: QueryHandle,
+        deltas: &Deltas,
+        vars: &[Symbol],
+        expected: &[[V; N]],
+    ) where
+        V: Type + Clone,
     {
         let idxs = self.get_indexes(handle, vars);
         let mut results = HashSet::<[Value; N]>::default();
-        self.eval_query(handle, |tuple| {
+        self.eval_query_with_deltas(handle, deltas, |tuple| {
             let vec: Vec<Value> = idxs.iter().map(|&i| tuple[i]).collect();
             results.insert(vec.try_into().unwrap());
         });
@@ -77,5 +95,474 @@ fn same_var() {
         .insert_arrays(&[[1, 2, 3], [1, 2, 1], [1, 1, 2], [2, 1, 1]]);
 
     let q1 = db.add_query(query!(R(a, a, b)));
-    db.eval_and_check(q1, &[a, b], &[[1, 1]]);
+    db.eval_and_check(q1, &[a, b], &[[1, 2]]);
+}
+
+#[test]
+fn versions() {
+    crate::symbols!(R, a, b);
+    let mut db = Database::default();
+    db.add_relation(R, 2)
+        .insert_arrays(&[[1, 2], [2, 3], [3, 4], [4, 5]]);
+
+    let mut deltas = Deltas::default();
+    deltas.insert(R, 2..3);
+
+    let stable = db.add_versioned_query(query!(R(a, b)), vec![Version::Stable]);
+    let recent = db.add_versioned_query(query!(R(a, b)), vec![Version::Recent]);
+    let all = db.add_versioned_query(query!(R(a, b)), vec![Version::All]);
+    db.eval_and_check_with_deltas(stable, &deltas, &[a, b], &[[1, 2], [2, 3]]);
+    db.eval_and_check_with_deltas(recent, &deltas, &[a, b], &[[3, 4]]);
+    db.eval_and_check_with_deltas(all, &deltas, &[a, b], &[[1, 2], [2, 3], [3, 4]]);
+    db.eval_and_check(all, &[a, b], &[[1, 2], [2, 3], [3, 4], [4, 5]]);
+}
+
+#[test]
+fn remove() {
+    crate::symbols!(R, a, b, c);
+    let mut db = Database::default();
+    db.add_relation(R, 2)
+        .insert_arrays(&[[1, 2], [1, 3], [2, 3], [3, 4], [4, 5], [5, 6]]);
+    let stable = db.add_versioned_query(query!(R(a, b)), vec![Version::Stable]);
+    let recent = db.add_versioned_query(query!(R(a, b)), vec![Version::Recent]);
+    let join = db.add_query(query!(R(a, b), R(b, c)));
+
+    let tuple = |a: i32, b: i32| vec![a.to_value(), b.to_value()];
+    let rel = db.relations.get_mut(&R).unwrap();
+    assert_eq!(rel.remove(&vec![tuple(1, 2)].into_iter().collect()), vec![]);
+    assert_eq!(rel.rows.len(), 6);
+    assert_eq!(rel.len(), 5);
+    assert_eq!(rel.iter().next(), Some(&tuple(1, 3)[..]));
+
+    // the rows after the removed one stay where they were
+    let mut deltas = Deltas::default();
+    deltas.insert(R, 1..3);
+    db.eval_and_check_with_deltas::<i32, 2>(stable, &deltas, &[a, b], &[]);
+    db.eval_and_check_with_deltas(recent, &deltas, &[a, b], &[[1, 3], [2, 3]]);
+    let expected = [[1, 3, 4], [2, 3, 4], [3, 4, 5], [4, 5, 6]];
+    db.eval_and_check(join, &[a, b, c], &expected);
+
+    // once half the rows are empty they are freed
+    let rel = db.relations.get_mut(&R).unwrap();
+    let removed = vec![tuple(1, 3), tuple(2, 3)].into_iter().collect();
+    assert_eq!(rel.remove(&removed), vec![0, 1, 2]);
+    assert_eq!(rel.rows.len(), 3);
+    db.eval_and_check(join, &[a, b, c], &[[3, 4, 5], [4, 5, 6]]);
+}
+
+#[test]
+fn negation() {
+    crate::symbols!(R, S, a, b, c);
+    let mut db = Database::default();
+    db.add_relation(R, 2)
+        .insert_arrays(&[[1, 2], [2, 3], [3, 4], [1, 3]]);
+    db.add_relation(S, 1).insert_arrays(&[[3]]);
+
+    let q1 = db.add_query(query!(R(a, b), R(b, c); !R(a, c)));
+    db.eval_and_check(q1, &[a, b, c], &[[1, 3, 4], [2, 3, 4]]);
+
+    let q2 = db.add_query(query!(R(a, b); !S(b), !S(a)));
+    db.eval_and_check(q2, &[a, b], &[[1, 2]]);
+}
+
+#[test]
+fn constants() {
+    crate::symbols!(R, a, b);
+    let mut db = Database::default();
+    db.add_relation(R, 3)
+        .insert_arrays(&[[1, 2, 3], [1, 2, 1], [1, 1, 2], [2, 1, 1]]);
+
+    let q1 = db.add_query(query!(R(1, a, b)));
+    db.eval_and_check(q1, &[a, b], &[[2, 3], [2, 1], [1, 2]]);
+
+    let q2 = db.add_query(query!(R(a, 2, a)));
+    db.eval_and_check(q2, &[a], &[[1]]);
+
+    let q3 = db.add_query(query!(R(a, b, 3), R(b, 1, 1)));
+    db.eval_and_check(q3, &[a, b], &[[1, 2]]);
+
+    let q4 = db.add_query(query!(R(a, b, 3), R(3, 1, 1)));
+    db.eval_and_check::<i32, 2>(q4, &[a, b], &[]);
+}
+
+#[test]
+fn shared_indexes() {
+    crate::symbols!(R, a, b, c);
+    let mut db = Database::default();
+    db.add_relation(R, 2).insert_arrays(&[[1, 2], [2, 3]]);
+
+    let q1 = db.add_query(query!(R(a, b), R(b, c)));
+    let q2 = db.add_query(query!(R(1, b), R(b, c)));
+    assert_eq!(db.relations[&R].indexes.len(), 2);
+
+    // the indexes pick up tuples inserted after the queries were added
+    db.relations[&R].insert_arrays(&[[3, 4]]);
+    db.eval_and_check(q1, &[a, b, c], &[[1, 2, 3], [2, 3, 4]]);
+    db.eval_and_check(q2, &[b, c], &[[2, 3]]);
+
+    let mut deltas = Deltas::default();
+    deltas.insert(R, 2..3);
+    let q3 = db.add_versioned_query(
+        query!(R(a, b), R(b, c)),
+        vec![Version::Stable, Version::Recent],
+    );
+    db.eval_and_check_with_deltas(q3, &deltas, &[a, b, c], &[[2, 3, 4]]);
+}
+
+#[test]
+fn copy_tuples() {
+    crate::symbols!(R, S, a, b);
+    let mut db = Database::default();
+    let rel = db.add_relation(R, 2);
+    rel.set_lattice(Min);
+    rel.insert_arrays(&[[1, 7], [2, 3], [1, 5]]);
+    rel.add_sorted_index(&[1]);
+
+    // the copy merges like the original, but has no indexes until a query
+    // reads it
+    let mut copy = db.relations[&R].copy_tuples();
+    assert!(copy.indexes.is_empty());
+    copy.insert_arrays(&[[2, 1], [1, 6]]);
+    copy.compact();
+    assert_eq!(copy.len(), 2);
+    assert_eq!(db.relations[&R].len(), 2);
+    let empty = db.relations[&R].empty_copy();
+    assert!(empty.is_empty() && empty.merges());
+    db.relations.insert(S, copy);
+    let q = db.add_query(query!(S(a, b)));
+    assert_eq!(db.relations[&S].indexes.len(), 1);
+    db.eval_and_check(q, &[a, b], &[[1, 5], [2, 1]]);
+}
+
+#[test]
+fn cost_based_order() {
+    crate::symbols!(R, S, T, a, b, c);
+    let mut db = Database::default();
+    let mut pairs = vec![];
+    for i in 0..10 {
+        for j in 0..10 {
+            pairs.push([i, j]);
+        }
+    }
+    db.add_relation(R, 2).insert_arrays(&pairs);
+    db.add_relation(S, 2).insert_arrays(&pairs);
+    db.add_relation(T, 1).insert_arrays(&[[3]]);
+
+    // b and c both appear twice, but T only has a single value for c
+    let q = db.add_query(query!(R(a, b), S(b, c), T(c)));
+    let order: Vec<Symbol> = db.queries[&q].by_var.keys().copied().collect();
+    assert_eq!(order, vec![c, b, a]);
+
+    // once T is much bigger, c is no longer the only cheap place to start
+    let mut many = vec![];
+    for i in 0..1000 {
+        many.push([i]);
+    }
+    db.relations[&T].insert_arrays(&many);
+    db.relations[&R].insert_arrays(&[[0, 20]]);
+    assert!(db.refresh_plan(q, &Deltas::default()));
+    let order: Vec<Symbol> = db.queries[&q].by_var.keys().copied().collect();
+    assert_eq!(order[0], b);
+    assert_eq!(db.collect(q).len(), 10 * 10 * 10 * 3);
+    // the old plan's indexes are dropped
+    let indexes: usize = db.relations.values().map(|r| r.indexes.len()).sum();
+    assert_eq!(indexes, db.queries[&q].indexes().count());
+}
+
+#[test]
+fn replan_in_transaction() {
+    crate::symbols!(R, S, T, a, b, c);
+    let mut db = Database::default();
+    let pairs: Vec<[i32; 2]> = (0..100).map(|i| [i / 10, i % 10]).collect();
+    db.add_relation(R, 2).insert_arrays(&pairs);
+    db.add_relation(S, 2).insert_arrays(&pairs);
+    db.add_relation(T, 1).insert_arrays(&[[3]]);
+    let q = db.add_query(query!(R(a, b), S(b, c), T(c)));
+    let many: Vec<[i32; 1]> = (0..1000).map(|i| [i]).collect();
+    db.relations[&T].insert_arrays(&many);
+
+    // the old plan's indexes are still there once the replan is rolled back
+    let mut tx = db.begin();
+    assert!(tx.refresh_plan(q, &Deltas::default()));
+    tx.rollback();
+    let order: Vec<Symbol> = db.queries[&q].by_var.keys().copied().collect();
+    assert_eq!(order, vec![c, b, a]);
+    assert_eq!(db.collect(q).len(), 10 * 10 * 10 * 3);
+
+    // and dropped once it is committed
+    let mut tx = db.begin();
+    assert!(tx.refresh_plan(q, &Deltas::default()));
+    tx.commit();
+    assert_eq!(db.collect(q).len(), 10 * 10 * 10 * 3);
+    let indexes: usize = db.relations.values().map(|r| r.indexes.len()).sum();
+    assert_eq!(indexes, db.queries[&q].indexes().count());
+}
+
+#[test]
+fn parallel_triangle() {
+    crate::symbols!(R, a, b, c);
+    let mut db = Database::default();
+    let n = 50;
+
+    let mut tuples = vec![];
+    for i in 0..n {
+        for j in 0..n {
+            if (i + j) % 3 != 0 {
+                tuples.push([i, j])
+            }
+        }
+    }
+
+    db.add_relation(R, 2).insert_arrays(&tuples);
+    let q1 = db.add_query(query!(R(a, b), R(b, c), R(c, a)));
+
+    let sequential = db.collect(q1);
+    let parts = db.par_eval_query(q1, &Deltas::default(), |vec: &mut Vec<Value>, tuple| {
+        vec.extend_from_slice(tuple)
+    });
+    assert!(parts.len() > 1);
+    assert_eq!(sequential, parts.concat());
+}
+
+#[test]
+fn lattice() {
+    crate::symbols!(R, S, a, b);
+    let mut db = Database::default();
+    db.add_relation(R, 2).set_lattice(Min);
+    let rel = db.relations.get_mut(&R).unwrap();
+    rel.insert_arrays(&[[1, 7], [2, 3], [1, 5], [1, 6], [2, -1]]);
+    assert_eq!(rel.superseded().count(), 2);
+    assert_eq!(rel.compact(), vec![0, 1]);
+    let q = db.add_query(query!(R(a, b)));
+    db.eval_and_check(q, &[a, b], &[[1, 5], [2, -1]]);
+
+    // a join that is neither of the two values
+    struct Or;
+    impl Lattice for Or {
+        fn join(&self, a: Value, b: Value) -> Value {
+            (i32::from_value(a) | i32::from_value(b)).to_value()
+        }
+    }
+    let rel = db.add_relation(S, 2);
+    rel.insert_arrays(&[[1, 1], [1, 2], [2, 4]]);
+    rel.set_lattice(Or);
+    rel.insert_arrays(&[[2, 4], [2, 1]]);
+    rel.compact();
+    let q = db.add_query(query!(S(a, b)));
+    db.eval_and_check(q, &[a, b], &[[1, 3], [2, 5]]);
+}
+
+#[test]
+fn equivalence() {
+    crate::symbols!(R, a, b, r);
+    let mut db = Database::default();
+    let rel = db.add_relation(R, 2);
+    rel.set_equivalence();
+    rel.insert_arrays(&[[1, 2], [3, 4], [5, 5], [2, 3]]);
+    let tuple = |a: i32, b: i32| [a.to_value(), b.to_value()];
+    assert!(!rel.insert(&tuple(1, 4)));
+    assert!(rel.contains(&tuple(4, 1)));
+    assert!(!rel.contains(&tuple(1, 5)));
+    // one of the first two classes moved to the other
+    assert_eq!(rel.superseded().count(), 2);
+    // too few to free their rows
+    assert_eq!(rel.compact(), vec![]);
+    assert_eq!(rel.stored_len(), 5);
+    assert_eq!(rel.rows.iter().count(), 5);
+    // the classes {1, 2, 3, 4} and {5}
+    assert_eq!(rel.len(), 4 * 4 + 1);
+    let stats = rel.stats();
+    assert_eq!(stats.len, 17);
+    assert_eq!(stats.columns[1].max, Some(5.to_value()));
+    assert_eq!(stats.columns[1].min, Some(1.to_value()));
+
+    let q = db.add_query(query!(R(a, r), R(b, r)));
+    let mut expected = vec![[5, 5]];
+    for i in 1..=4 {
+        for j in 1..=4 {
+            expected.push([i, j]);
+        }
+    }
+    db.eval_and_check(q, &[a, b], &expected);
+}
+
+#[test]
+fn stats() {
+    crate::symbols!(R, S);
+    let mut db = Database::default();
+    let rel = db.add_relation(R, 2);
+    let tuples: Vec<[i32; 2]> = (0..5000).map(|i| [i % 100, -i]).collect();
+    rel.insert_arrays(&tuples);
+    rel.insert_arrays(&tuples[..10]);
+    db.add_relation(S, 3);
+
+    let stats = db.stats();
+    let r = &stats[&R];
+    assert_eq!(r.len, 5000);
+    // the distinct values are estimates
+    let close = |estimate: usize, n: f64| (estimate as f64 / n - 1.0).abs() < 0.1;
+    assert!(close(r.columns[0].distinct, 100.0));
+    assert!(close(r.columns[1].distinct, 5000.0));
+    assert_eq!(r.columns[0].min, Some(0.to_value()));
+    assert_eq!(r.columns[0].max, Some(99.to_value()));
+    assert_eq!(r.columns[1].min, Some((-4999).to_value()));
+    assert_eq!(r.columns[1].max, Some(0.to_value()));
+    assert_eq!(stats[&S].len, 0);
+    assert_eq!(stats[&S].columns[2].min, None);
+
+    // removing most of the tuples frees their rows and recomputes the columns
+    let rel = db.relations.get_mut(&R).unwrap();
+    let removed = tuples[1..].iter().map(|t| t.map(|v| v.to_value()).to_vec());
+    rel.remove(&removed.collect());
+    let stats = rel.stats();
+    assert_eq!(stats.len, 1);
+    assert_eq!(stats.columns[1].distinct, 1);
+    assert_eq!(stats.columns[1].min, Some(0.to_value()));
+}
+
+#[test]
+fn scan() {
+    use std::ops::Bound::{self, *};
+    crate::symbols!(R, a, b, c);
+    let mut db = Database::default();
+    let rel = db.add_relation(R, 3);
+    rel.insert_arrays(&[[1, 5, 0], [2, -3, 1], [1, -7, 2], [1, 12, 3], [2, 4, 4]]);
+    rel.add_sorted_index(&[0, 1]);
+    rel.insert_arrays(&[[1, 9, 5], [1, 3, 6]]);
+
+    let scan = |db: &Database, prefix: &[i32], range: (Bound<i32>, Bound<i32>)| {
+        let prefix: Vec<Value> = prefix.iter().map(|v| v.to_value()).collect();
+        let range = (range.0.map(i32::to_value), range.1.map(i32::to_value));
+        let mut last = vec![];
+        db.relations[&R].scan(&[0, 1], &prefix, range, |t| {
+            last.push(i32::from_value(t[2]))
+        });
+        last
+    };
+    // x < 10, sorted as signed integers
+    assert_eq!(scan(&db, &[1], (Unbounded, Excluded(10))), [2, 6, 0, 5]);
+    assert_eq!(scan(&db, &[1], (Included(3), Included(12))), [6, 0, 5, 3]);
+    assert_eq!(scan(&db, &[], (Included(2), Unbounded)), [1, 4]);
+    assert!(scan(&db, &[3], (Unbounded, Unbounded)).is_empty());
+
+    // the index outlives the queries that read it
+    let q = db.add_query(query!(R(a, b, 0)));
+    db.eval_and_check(q, &[a, b], &[[1, 5]]);
+    let q = db.add_query(query!(R(a, b, c)));
+    db.remove_query(q);
+    assert!(db.relations[&R].index(&[0, 1, 2]).is_some());
+    assert_eq!(scan(&db, &[2], (Unbounded, Unbounded)), [1, 4]);
+}
+
+#[test]
+fn transaction() {
+    crate::symbols!(R, S, T, a, b);
+    let mut db = Database::default();
+    db.add_relation(R, 2).insert_arrays(&[[1, 2], [2, 3]]);
+    db.add_relation(S, 2).set_lattice(Min);
+    db.relations
+        .get_mut(&S)
+        .unwrap()
+        .insert_arrays(&[[1, 5], [2, 4]]);
+    let q = db.add_query(query!(R(a, b), S(b, 4)));
+    db.eval_and_check(q, &[a, b], &[[1, 2]]);
+    let before = db.stats();
+
+    let mut tx = db.begin();
+    tx.relations
+        .get_mut(&R)
+        .unwrap()
+        .insert_arrays(&[[3, 2], [4, 5]]);
+    // merging replaces a tuple, so the relation is copied
+    tx.relations
+        .get_mut(&S)
+        .unwrap()
+        .insert_arrays(&[[1, 4], [5, 4]]);
+    tx.add_relation(T, 1);
+    let q2 = tx.add_query(query!(R(a, b), S(b, 4)));
+    tx.eval_and_check(q, &[a, b], &[[1, 2], [3, 2], [4, 5]]);
+    tx.rollback();
+
+    assert_eq!(db.stats(), before);
+    assert!(!db.relations.contains_key(&T));
+    assert_eq!(db.add_query(query!(R(a, b))), q2);
+    // the indexes forgot the new rows too
+    db.eval_and_check(q, &[a, b], &[[1, 2]]);
+    let rel = db.relations.get_mut(&R).unwrap();
+    rel.insert_arrays(&[[6, 2]]);
+    db.eval_and_check(q, &[a, b], &[[1, 2], [6, 2]]);
+
+    // dropping a transaction rolls it back too, committing keeps it
+    db.begin()
+        .relations
+        .get_mut(&R)
+        .unwrap()
+        .insert_arrays(&[[7, 2]]);
+    assert_eq!(db.relations[&R].len(), 3);
+    let mut tx = db.begin();
+    tx.relations.get_mut(&R).unwrap().insert_arrays(&[[7, 2]]);
+    tx.relations.get_mut(&S).unwrap().insert_arrays(&[[1, 4]]);
+    tx.commit();
+    db.eval_and_check(q, &[a, b], &[[1, 2], [6, 2], [7, 2]]);
+}
+
+#[test]
+fn save_and_load() {
+    use crate::Error;
+    crate::symbols!(R, S, T, U, a, b);
+    let mut db = Database::default();
+    let rel = db.add_relation(R, 2);
+    let schema = Schema::from_named_types(vec![(a, i32::type_id()), (b, i32::type_id())]);
+    rel.schema = Some(schema);
+    rel.insert_arrays(&[[1, -2], [3, i32::MAX], [i32::MIN, 0]]);
+    db.add_relation(S, 2).set_lattice(Min);
+    // the superseded tuple is not saved
+    db.relations
+        .get_mut(&S)
+        .unwrap()
+        .insert_arrays(&[[1, 5], [1, 3]]);
+    db.add_relation(U, 0).insert(&[]);
+
+    let path = std::env::temp_dir().join(format!("datastick-{}.db", std::process::id()));
+    db.save(&path).unwrap();
+    let mut loaded = Database::load(&path).unwrap();
+    let symbols: Vec<Symbol> = loaded.relations.keys().copied().collect();
+    assert_eq!(symbols, [R, S, U]);
+    assert_eq!(loaded.relations[&R].schema, db.relations[&R].schema);
+    assert_eq!(loaded.relations[&S].schema, None);
+    assert_eq!(loaded.stats()[&R], db.stats()[&R]);
+    assert_eq!(loaded.relations[&S].len(), 1);
+    assert_eq!(loaded.relations[&U].len(), 1);
+    let q = loaded.add_query(query!(R(a, b)));
+    loaded.eval_and_check(q, &[a, b], &[[1, -2], [3, i32::MAX], [i32::MIN, 0]]);
+    let q = loaded.add_query(query!(S(a, b)));
+    loaded.eval_and_check(q, &[a, b], &[[1, 3]]);
+
+    // a save that fails leaves the old file as it was
+    let schema = Schema::from_named_types(vec![(a, std::any::TypeId::of::<u8>())]);
+    db.add_relation(T, 1).schema = Some(schema);
+    let err = Error::UnsupportedType {
+        relation: T,
+        column: a,
+    };
+    assert_eq!(db.save(&path).err(), Some(err));
+    assert_eq!(Database::load(&path).unwrap().relations.len(), 3);
+    let name = path.file_name().unwrap().to_str().unwrap();
+    let siblings = std::fs::read_dir(path.parent().unwrap()).unwrap();
+    let names = siblings.map(|e| e.unwrap().file_name().into_string().unwrap());
+    assert_eq!(names.filter(|n| n.starts_with(name)).count(), 1);
+
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[20] ^= 1;
+    std::fs::write(&path, &bytes).unwrap();
+    let err = Error::CorruptFile("the checksum doesn't match".into());
+    assert_eq!(Database::load(&path).err(), Some(err));
+    bytes[4] = 2;
+    std::fs::write(&path, &bytes).unwrap();
+    assert_eq!(
+        Database::load(&path).err(),
+        Some(Error::UnsupportedVersion(2))
+    );
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(Database::load(&path), Err(Error::Io(_))));
 }
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+
+use crate::ast::Value;
+
+/// A trie over some columns of a relation. Every node remembers the row of
+/// the first tuple inserted under it, so a trie built from the rows of a
+/// relation in insertion order can be read as it was before any given row.
+/// The children of a node are sorted, so they can be scanned by range and
+/// intersected by merging.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct Trie {
+    first_row: usize,
+    children: BTreeMap<Value, Self>,
+}
+
+impl Trie {
+    /// Inserts the columns of `tuple` listed in `shuffle`, in that order.
+    pub fn insert(&mut self, shuffle: &[usize], tuple: &[Value], row: usize) {
+        debug_assert!(shuffle.len() <= tuple.len());
+        let mut trie = self;
+        for i in shuffle {
+            trie = trie.children.entry(tuple[*i]).or_insert_with(|| Trie {
+                first_row: row,
+                children: Default::default(),
+            });
+        }
+    }
+
+    /// Removes the nodes a tuple from row `bound` or later added, along its
+    /// path. Every node under them was added by later rows too.
+    pub fn prune(&mut self, shuffle: &[usize], tuple: &[Value], bound: usize) {
+        let mut trie = self;
+        for i in shuffle {
+            let val = tuple[*i];
+            match trie.children.get(&val) {
+                None => return,
+                Some(child) if child.first_row >= bound => {
+                    trie.children.remove(&val);
+                    return;
+                }
+                Some(_) => trie = trie.children.get_mut(&val).unwrap(),
+            }
+        }
+    }
+
+    /// Removes a tuple inserted at `row` from a trie over all of its
+    /// columns, along with the nodes it was the only tuple under. The nodes
+    /// it was the first tuple under take the row of the next one.
+    pub fn remove(&mut self, shuffle: &[usize], tuple: &[Value], row: usize) {
+        let Some((&i, rest)) = shuffle.split_first() else {
+            return;
+        };
+        let val = tuple[i];
+        let Some(child) = self.children.get_mut(&val) else {
+            return;
+        };
+        child.remove(rest, tuple, row);
+        if child.children.is_empty() {
+            self.children.remove(&val);
+        } else if child.first_row == row {
+            let first = child.children.values().map(|c| c.first_row).min();
+            child.first_row = first.unwrap();
+        }
+    }
+
+    /// A view of this trie with only the tuples from rows before `bound`.
+    pub fn view(&self, bound: usize) -> TrieRef<'_> {
+        TrieRef { trie: self, bound }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct TrieRef<'a> {
+    trie: &'a Trie,
+    bound: usize,
+}
+
+impl<'a> TrieRef<'a> {
+    /// The visible children with keys in a range, in order.
+    pub fn range(
+        &self,
+        range: impl RangeBounds<Value>,
+    ) -> impl Iterator<Item = (Value, Self)> + 'a {
+        let bound = self.bound;
+        self.trie
+            .children
+            .range(range)
+            .filter(move |(_, child)| child.first_row < bound)
+            .map(move |(val, child)| (*val, child.view(bound)))
+    }
+
+    /// The first visible key after `lower`.
+    pub fn seek(&self, lower: Bound<Value>) -> Option<Value> {
+        self.range((lower, Bound::Unbounded))
+            .map(|(val, _)| val)
+            .next()
+    }
+
+    pub fn get(&self, val: &Value) -> Option<Self> {
+        let child = self.trie.children.get(val)?;
+        (child.first_row < self.bound).then(|| child.view(self.bound))
+    }
+
+    pub fn get_path(&self, path: &[Value]) -> Option<Self> {
+        let mut trie = *self;
+        for val in path {
+            trie = trie.get(val)?;
+        }
+        Some(trie)
+    }
+
+    /// Calls `f` with the row of each visible node `depth` levels down, in
+    /// the order of the trie. In a trie over every column, the leaves are
+    /// the rows of the tuples.
+    pub fn for_each_row(&self, depth: usize, f: &mut impl FnMut(usize)) {
+        if depth == 0 {
+            return f(self.trie.first_row);
+        }
+        for (_, child) in self.range(..) {
+            child.for_each_row(depth - 1, f);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::ast::Value;
+use crate::util::IndexMap;
+
+/// The classes of an equivalence relation. Every element points directly to
+/// the representative of its class, and merging two classes moves the
+/// elements of the smaller one, so each element moves a logarithmic number
+/// of times.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct UnionFind {
+    roots: IndexMap<Value, Value>,
+    // the elements of each class, by representative
+    classes: IndexMap<Value, Vec<Value>>,
+    // the number of pairs of equivalent elements
+    pairs: usize,
+}
+
+impl UnionFind {
+    pub fn find(&self, x: Value) -> Option<Value> {
+        self.roots.get(&x).copied()
+    }
+
+    /// Merges the classes of two elements, adding the elements that are new.
+    /// Returns the elements whose representative changed, with their old
+    /// representative if they had one.
+    pub fn union(&mut self, a: Value, b: Value) -> Vec<(Value, Option<Value>)> {
+        let mut moved = vec![];
+        for x in [a, b] {
+            if !self.roots.contains_key(&x) {
+                self.roots.insert(x, x);
+                self.classes.insert(x, vec![x]);
+                self.pairs += 1;
+                moved.push((x, None));
+            }
+        }
+
+        let (ra, rb) = (self.roots[&a], self.roots[&b]);
+        if ra == rb {
+            return moved;
+        }
+        let (small, large) = match self.classes[&ra].len() < self.classes[&rb].len() {
+            true => (ra, rb),
+            false => (rb, ra),
+        };
+        let elements = self.classes.swap_remove(&small).unwrap();
+        self.pairs += 2 * elements.len() * self.classes[&large].len();
+        for &x in &elements {
+            self.roots[&x] = large;
+            // new elements only need their final representative
+            if !moved.iter().any(|(y, _)| *y == x) {
+                moved.push((x, Some(small)));
+            }
+        }
+        self.classes[&large].extend(elements);
+        moved
+    }
+
+    /// The number of pairs of equivalent elements, in either order and
+    /// including each element with itself.
+    pub fn pairs(&self) -> usize {
+        self.pairs
+    }
+
+    pub fn classes(&self) -> impl Iterator<Item = &[Value]> + '_ {
+        self.classes.values().map(|class| class.as_slice())
+    }
+
+    pub fn clear(&mut self) {
+        self.roots.clear();
+        self.classes.clear();
+        self.pairs = 0;
+    }
+
+    /// Rebuilds the classes from the tuples of elements and their
+    /// representatives.
+    pub fn rebuild<'a>(&mut self, tuples: impl Iterator<Item = &'a [Value]>) {
+        self.clear();
+        for tuple in tuples {
+            let (x, root) = (tuple[0], tuple[1]);
+            self.roots.insert(x, root);
+            self.classes.entry(root).or_default().push(x);
+        }
+        self.pairs = self.classes.values().map(|c| c.len() * c.len()).sum();
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt::{Display, Formatter};
+
+use crate::ast::Variable;
+use crate::util::Symbol;
+use crate::Limit;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Parse(String),
+    UnsafeNegation {
+        relation: Symbol,
+        variable: Variable,
+    },
+    UnboundHeadVariable {
+        relation: Symbol,
+        variable: Variable,
+    },
+    NegativeCycle(Symbol),
+    RecursiveAggregate(Symbol),
+    MisplacedAggregate(Symbol),
+    UnknownColumn {
+        relation: Symbol,
+        column: Symbol,
+    },
+    InvalidEquivalence(Symbol),
+    RetractEquivalence(Symbol),
+    AssertionFailed(Symbol, Symbol),
+    StoppedEarly(Limit),
+    UnsupportedType {
+        relation: Symbol,
+        column: Symbol,
+    },
+    Io(String),
+    UnsupportedVersion(u32),
+    SchemaMismatch(Symbol),
+    CorruptFile(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse(msg) => write!(f, "parse error: {}", msg),
+            Error::UnsafeNegation { relation, variable } => write!(
+                f,
+                "variable {} in a rule for {} only appears in negated atoms",
+                variable, relation
+            ),
+            Error::UnboundHeadVariable { relation, variable } => write!(
+                f,
+                "variable {} in the head {} is not bound by a positive body atom",
+                variable, relation
+            ),
+            Error::NegativeCycle(relation) => write!(
+                f,
+                "{} depends on the negation of a relation in its own recursive cycle",
+                relation
+            ),
+            Error::RecursiveAggregate(relation) => write!(
+                f,
+                "{} aggregates over a relation in its own recursive cycle",
+                relation
+            ),
+            Error::MisplacedAggregate(relation) => write!(
+                f,
+                "aggregates can only appear in rule heads, found one in {}",
+                relation
+            ),
+            Error::UnknownColumn { relation, column } => {
+                write!(f, "{} has no column named {}", relation, column)
+            }
+            Error::InvalidEquivalence(relation) => write!(
+                f,
+                "equivalence relation {} needs two columns and no key",
+                relation
+            ),
+            Error::RetractEquivalence(relation) => write!(
+                f,
+                "can't retract from the equivalence relation {}",
+                relation
+            ),
+            Error::AssertionFailed(a, b) => {
+                write!(f, "assertion failed: {} and {} have different tuples", a, b)
+            }
+            Error::StoppedEarly(limit) => {
+                write!(f, "evaluation was stopped early by {}", limit)
+            }
+            Error::UnsupportedType { relation, column } => write!(
+                f,
+                "column {} of {} has a type that can't be saved",
+                column, relation
+            ),
+            Error::Io(msg) => write!(f, "I/O error: {}", msg),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported database file version {}", version)
+            }
+            Error::CorruptFile(reason) => write!(f, "corrupt database file: {}", reason),
+            Error::SchemaMismatch(relation) => write!(
+                f,
+                "{} was saved with other columns than it is declared with",
+                relation
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
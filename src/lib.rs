@@ -1,80 +1,753 @@
-use std::convert::TryInto;
+use std::{
+    cmp::Ordering,
+    convert::TryInto,
+    ops::ControlFlow,
+    path::Path,
+    sync::atomic::{self, AtomicUsize},
+};
 
 use ast::*;
-use db::QueryHandle;
-use util::Symbol;
+use db::{Deltas, QueryHandle, Tuples, Version};
+use rayon::prelude::*;
+use util::{HashSet, IndexMap, IndexSet, Symbol};
+
+pub use budget::{Budget, CancellationToken, Limit, Outcome};
+pub use error::Error;
+pub use provenance::Proof;
+pub use transaction::Transaction;
 
 pub mod ast;
+mod budget;
 pub mod db;
+mod error;
+mod magic;
 mod parse;
+mod provenance;
+mod strata;
+mod transaction;
 pub mod util;
 
+use budget::Meter;
+use provenance::{Derivation, Premises, Provenance};
+use strata::Stratum;
+
+/// The values of the variables of a query in one of its solutions.
+pub type Bindings = IndexMap<Variable, Value>;
+
 #[derive(Default)]
 pub struct DatalogContext {
     db: db::Database,
-    rules: Vec<(Rule, QueryHandle)>,
+    rules: Vec<(Rule, Vec<QueryHandle>)>,
+    strata: Vec<Stratum>,
+    // rules are evaluated on rayon's global pool unless this is set
+    pool: Option<rayon::ThreadPool>,
+    // the tuples inserted directly into relations that rules derive into,
+    // so they survive when those relations are recomputed
+    base_facts: IndexMap<Symbol, IndexSet<Vec<Value>>>,
+    // for each rule, one query per head that finds the derivations of the
+    // head's tuples, built the first time one of them is retracted
+    rederive: IndexMap<usize, Vec<QueryHandle>>,
+    // how each derived tuple was first derived, if provenance is on
+    provenance: Option<Provenance>,
+    // whether goals are answered with magic sets, see `answer`
+    magic_sets: bool,
+    budget: Budget,
+    cancellation: CancellationToken,
+    // how to undo the changes made since the transaction began, if one is open
+    savepoint: Option<transaction::Savepoint>,
 }
 
 impl DatalogContext {
-    pub fn add_rule(&mut self, rule: Rule) {
-        let handle = self.db.add_query(rule.body.clone());
-        self.rules.push((rule, handle));
+    /// Evaluates rules on a dedicated pool with this many threads.
+    pub fn set_num_threads(&mut self, n: usize) {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(n).build();
+        self.pool = Some(pool.expect("Failed to build thread pool"));
+    }
+
+    /// Turns recording how each tuple was first derived on or off, see
+    /// [`explain`](Self::explain). Tuples derived while it is off look like
+    /// base facts, so it should be turned on before running.
+    pub fn set_provenance(&mut self, enabled: bool) {
+        if enabled == self.provenance.is_some() {
+            return;
+        }
+        self.modify_provenance();
+        match enabled {
+            true => {
+                self.provenance.get_or_insert_with(Provenance::default);
+            }
+            false => self.provenance = None,
+        }
+    }
+
+    /// Limits every following [`run`](Self::run).
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = budget;
+    }
+
+    /// A token that stops the current or next run of this context when it
+    /// is cancelled, from any thread.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Turns answering goals with magic sets on or off, see
+    /// [`answer`](Self::answer). A program that only asks queries is
+    /// evaluated with them either way, see [`eval`](Self::eval).
+    pub fn set_magic_sets(&mut self, enabled: bool) {
+        self.magic_sets = enabled;
+    }
+
+    /// Returns the tuples of the goal's relation that match it. Its values
+    /// have to be equal, and so do the columns of a repeated variable.
+    ///
+    /// With magic sets on, a goal with a value over a derived relation is
+    /// answered by a copy of the program rewritten to only derive what the
+    /// goal depends on, which leaves this context untouched. Otherwise the
+    /// context runs to a fixpoint first.
+    ///
+    /// Fails if the run is stopped early, see [`run`](Self::run).
+    pub fn answer(&mut self, goal: &Atom) -> Result<Vec<Vec<Value>>, Error> {
+        self.answer_with(goal, self.magic_sets)
+    }
+
+    fn answer_with(&mut self, goal: &Atom, magic_sets: bool) -> Result<Vec<Vec<Value>>, Error> {
+        let rules: Vec<&Rule> = self.rules.iter().map(|(r, _)| r).collect();
+        let merges = |sym| self.db.relations[&sym].merges();
+        let program = match magic_sets {
+            true => magic::transform(&rules, goal, merges),
+            false => None,
+        };
+        let Some(program) = program else {
+            self.run().fixpoint()?;
+            let mut answers = vec![];
+            self.for_each(goal.relation, |tuple| {
+                if goal.matches(tuple) {
+                    answers.push(tuple.to_vec());
+                }
+            });
+            return Ok(answers);
+        };
+
+        let mut ctx = DatalogContext {
+            budget: self.budget,
+            cancellation: self.cancellation.clone(),
+            ..Default::default()
+        };
+        for rule in &program.rules {
+            let atoms = rule.body.atoms.iter().chain(&rule.body.negated);
+            for atom in atoms.chain(&rule.head) {
+                let sym = atom.relation;
+                if ctx.db.relations.contains_key(&sym) {
+                    continue;
+                }
+                if let Some(&original) = program.adorned.get(&sym) {
+                    let arity = self.db.relations[&original].arity;
+                    ctx.db.add_relation(sym, arity);
+                } else if let Some(&arity) = program.magic.get(&sym) {
+                    ctx.db.add_relation(sym, arity);
+                } else {
+                    // derived relations start over from their base facts, and
+                    // the rewritten rules only build the indexes they read
+                    let rel = &self.db.relations[&sym];
+                    let copy = match self.base_facts.get(&sym) {
+                        Some(base) => {
+                            let mut copy = rel.empty_copy();
+                            for tuple in base {
+                                copy.insert(tuple);
+                            }
+                            copy.compact();
+                            copy
+                        }
+                        None => rel.copy_tuples(),
+                    };
+                    ctx.db.relations.insert(sym, copy);
+                }
+            }
+        }
+        for rule in program.rules {
+            ctx.add_rule(rule)
+                .expect("magic rules are as valid as the originals");
+        }
+        for fact in &program.facts {
+            ctx.add_fact(fact);
+        }
+        ctx.run().fixpoint()?;
+
+        let mut answers = vec![];
+        ctx.for_each(program.goal, |tuple| {
+            if goal.matches(tuple) {
+                answers.push(tuple.to_vec());
+            }
+        });
+        Ok(answers)
     }
 
+    /// Answers a query with the values of its variables in each of its
+    /// solutions, after running the context to a fixpoint. The query is
+    /// compiled like a rule body and dropped once it is answered. With magic
+    /// sets on, a query of a single atom is answered by
+    /// [`answer`](Self::answer) instead.
+    ///
+    /// Fails if a negated atom uses a variable no positive atom binds, if
+    /// an aggregate appears in the query, or if the run is stopped early.
+    pub fn query(&mut self, query: Query) -> Result<Vec<Bindings>, Error> {
+        self.query_with(query, self.magic_sets)
+    }
+
+    fn query_with(&mut self, query: Query, magic_sets: bool) -> Result<Vec<Bindings>, Error> {
+        let atoms = query.atoms.iter().chain(&query.negated);
+        if let Some(atom) = atoms.clone().find(|a| a.has_aggregate()) {
+            return Err(Error::MisplacedAggregate(atom.relation));
+        }
+        if let Some(variable) = query.unsafe_var() {
+            let atom = query.negated.iter().find(|a| a.has_var(variable));
+            let relation = atom.unwrap().relation;
+            return Err(Error::UnsafeNegation { relation, variable });
+        }
+        // wildcards became variables that can't be written
+        let vars: IndexSet<Variable> = query
+            .atoms
+            .iter()
+            .flat_map(|a| a.vars())
+            .filter(|&v| !is_wildcard(v))
+            .collect();
+        let vars: Vec<Variable> = vars.into_iter().collect();
+
+        let mut answers: IndexSet<Vec<Value>> = IndexSet::default();
+        if magic_sets && query.atoms.len() == 1 && query.negated.is_empty() {
+            let atom = &query.atoms[0];
+            let columns: Vec<usize> = vars
+                .iter()
+                .map(|&v| {
+                    atom.terms
+                        .iter()
+                        .position(|t| *t == Term::Variable(v))
+                        .unwrap()
+                })
+                .collect();
+            for tuple in self.answer_with(atom, magic_sets)? {
+                answers.insert(columns.iter().map(|&i| tuple[i]).collect());
+            }
+        } else {
+            self.run().fixpoint()?;
+            let handle = self.db.add_query(self.expand_equivalences(&query));
+            let indexes = self.db.get_indexes(handle, &vars);
+            self.db.eval_query(handle, |subst| {
+                answers.insert(indexes.iter().map(|&i| subst[i]).collect());
+            });
+            self.db.remove_query(handle);
+        }
+
+        let answers = answers.into_iter().map(|values| {
+            let bindings = vars.iter().copied().zip(values);
+            bindings.collect()
+        });
+        Ok(answers.collect())
+    }
+
+    pub fn parse_and_query(&mut self, s: &str) -> Result<Vec<Bindings>, Error> {
+        let parser = parse::QueryParser::new();
+        let query = parser.parse(s).map_err(|e| Error::Parse(e.to_string()))?;
+        self.query(query)
+    }
+
+    /// Rebuilds the proof of a tuple down to base facts, from the rule and
+    /// body tuples that first derived each tuple. Returns `None` if the
+    /// tuple is not in the relation or provenance is off.
+    pub fn explain(&self, relation: Symbol, tuple: &[Value]) -> Option<Proof<'_>> {
+        let provenance = self.provenance.as_ref()?;
+        if !self.db.relations[&relation].contains(tuple) {
+            return None;
+        }
+        let rules = |r: usize| &self.rules[r].0;
+        Some(provenance.proof(&rules, relation, tuple))
+    }
+
+    /// Adds a rule, compiled into one delta query per body atom for
+    /// semi-naive evaluation. The `i`th delta query reads only the recent
+    /// tuples of atom `i`, the stable tuples of the atoms before it, and all
+    /// tuples of the atoms after it, so every derivation that uses at least
+    /// one recent tuple is found exactly once. Aggregate rules are not
+    /// incremental, they get a single query over all tuples. So do rules
+    /// without positive atoms, which fire once each time their stratum is
+    /// computed.
+    ///
+    /// Fails if a head or negated atom uses a variable no positive atom
+    /// binds, if an aggregate appears in the body, or if the rule makes a
+    /// relation depend on its own negation or aggregate.
+    pub fn add_rule(&mut self, rule: Rule) -> Result<(), Error> {
+        let body = &rule.body;
+        if let Some(atom) = body
+            .atoms
+            .iter()
+            .chain(&body.negated)
+            .find(|a| a.has_aggregate())
+        {
+            return Err(Error::MisplacedAggregate(atom.relation));
+        }
+        if let Some(variable) = rule.body.unsafe_var() {
+            let relation = rule.head.first().map_or(variable, |a| a.relation);
+            return Err(Error::UnsafeNegation { relation, variable });
+        }
+        if let Some((relation, variable)) = rule.unbound_head_var() {
+            return Err(Error::UnboundHeadVariable { relation, variable });
+        }
+
+        let mut rules: Vec<&Rule> = self.rules.iter().map(|(r, _)| r).collect();
+        rules.push(&rule);
+        let old_strata = std::mem::replace(&mut self.strata, strata::stratify(&rules)?);
+        // strata that gained a rule have to be recomputed from scratch
+        for stratum in &mut self.strata {
+            match old_strata.iter().find(|old| old.rules == stratum.rules) {
+                Some(old) => {
+                    stratum.seen = old.seen.clone();
+                    stratum.dirty = old.dirty;
+                    stratum.started = old.started;
+                }
+                None => stratum.dirty = true,
+            }
+        }
+        for atom in &rule.head {
+            let rel = &self.db.relations[&atom.relation];
+            let base = self.base_facts.entry(atom.relation);
+            base.or_insert_with(|| rel.rows.iter().map(<[Value]>::to_vec).collect());
+        }
+
+        let body = self.expand_equivalences(&rule.body);
+        // rules without positive atoms are evaluated whole, like aggregates
+        if rule.is_aggregate() || body.atoms.is_empty() {
+            let handle = self.db.add_query(body);
+            self.rules.push((rule, vec![handle]));
+            return Ok(());
+        }
+
+        let n = body.atoms.len();
+        let handles = (0..n)
+            .map(|i| {
+                let versions = (0..n)
+                    .map(|j| match j.cmp(&i) {
+                        Ordering::Less => Version::Stable,
+                        Ordering::Equal => Version::Recent,
+                        Ordering::Greater => Version::All,
+                    })
+                    .collect();
+                self.db.add_versioned_query(body.clone(), versions)
+            })
+            .collect();
+        self.rules.push((rule, handles));
+        Ok(())
+    }
+
+    /// Adds a fact. If the context already reached a fixpoint, the next
+    /// [`run`](Self::run) only propagates the consequences of new facts.
     pub fn add_fact(&mut self, fact: &Atom) {
-        let rel = &mut self.db.relations[&fact.relation];
         let values: Vec<Value> = fact.terms.iter().map(Term::eval).collect();
-        rel.insert(&values)
+        self.insert_many(fact.relation, &values)
+    }
+
+    /// Retracts a fact, see [`retract_many`](Self::retract_many).
+    pub fn retract(&mut self, fact: &Atom) -> Result<Outcome, Error> {
+        let values: Vec<Value> = fact.terms.iter().map(Term::eval).collect();
+        self.retract_many(fact.relation, &values)
+    }
+
+    /// Retracts facts and removes the derived tuples that lost their support
+    /// by deleting and rederiving. Facts added since the last
+    /// [`run`](Self::run) are propagated first, and the context is left at a
+    /// fixpoint.
+    ///
+    /// Going through the strata in order, the delta queries of each stratum
+    /// are evaluated with the deleted tuples as the recent ones, which
+    /// over-deletes every tuple that has a derivation using them. Once all of
+    /// them are removed, the over-deleted tuples that still have a derivation
+    /// are put back and propagated like new facts. Strata that negate or
+    /// aggregate over a relation that lost tuples are recomputed instead.
+    ///
+    /// Both runs count against the [`Budget`]. If the first one stops early,
+    /// the facts are removed and every stratum is recomputed by the next
+    /// run. If rederiving stops early, the strata it did not get to are.
+    ///
+    /// Equivalence relations only ever merge classes, so retracting from one
+    /// is an error and leaves the context as it was.
+    pub fn retract_many(&mut self, relation: Symbol, tuples: &[Value]) -> Result<Outcome, Error> {
+        if self.db.relations[&relation].is_equivalence() {
+            return Err(Error::RetractEquivalence(relation));
+        }
+        let mut meter = Meter::new(self.budget, self.cancellation.clone());
+        let result = self.run_metered(&mut meter);
+
+        let arity = self.db.relations[&relation].arity;
+        self.modify_base_facts();
+        let rel = &self.db.relations[&relation];
+        let mut deleted = Tuples::default();
+        for tuple in tuples.chunks_exact(arity) {
+            if let Some(base) = self.base_facts.get_mut(&relation) {
+                base.shift_remove(tuple);
+            }
+            if rel.rows.contains(tuple) {
+                deleted.entry(relation).or_default().insert(tuple.to_vec());
+            }
+        }
+        if let Err(limit) = result {
+            // deleting and rederiving needs a fixpoint to start from
+            if let Some(tuples) = deleted.get(&relation) {
+                self.db.relations.get_mut(&relation).unwrap().remove(tuples);
+            }
+            for stratum in &mut self.strata {
+                stratum.dirty = true;
+            }
+            return Ok(meter.outcome(Some(limit)));
+        }
+
+        for i in 0..self.strata.len() {
+            self.overdelete(i, &mut deleted);
+        }
+        for (sym, tuples) in &deleted {
+            self.db.relations.get_mut(sym).unwrap().remove(tuples);
+            if let Some(provenance) = &mut self.provenance {
+                provenance.remove(*sym, tuples);
+            }
+        }
+        // the rows moved, and every stratum already accounted for the removal
+        for stratum in &mut self.strata {
+            for (sym, seen) in &mut stratum.seen {
+                *seen = self.db.relations[sym].rows.len();
+            }
+        }
+
+        let mut cleared = HashSet::default();
+        for i in 0..self.strata.len() {
+            self.rederive(i, &deleted);
+            if let Err(limit) = self.run_stratum(i, &mut cleared, &mut meter) {
+                for stratum in &mut self.strata[i + 1..] {
+                    stratum.dirty = true;
+                }
+                return Ok(meter.outcome(Some(limit)));
+            }
+        }
+        Ok(meter.outcome(None))
+    }
+
+    /// Adds the tuples of a stratum's relations that have a derivation using
+    /// a deleted tuple to the deleted tuples.
+    fn overdelete(&mut self, stratum: usize, deleted: &mut Tuples) {
+        let Self {
+            db, rules, strata, ..
+        } = self;
+        let stratum = &mut strata[stratum];
+        let compiled: Vec<&(Rule, Vec<QueryHandle>)> =
+            stratum.rules.iter().map(|&r| &rules[r]).collect();
+        let rules: Vec<&Rule> = compiled.iter().map(|(rule, _)| rule).collect();
+
+        let lost = |a: &Atom| deleted.get(&a.relation).is_some_and(|d| !d.is_empty());
+        let reads_lost = rules
+            .iter()
+            .any(|r| r.body.atoms.iter().chain(&r.body.negated).any(lost));
+        if !reads_lost {
+            return;
+        }
+
+        let monotonic = rules.iter().all(|r| {
+            let merged = r.head.iter().any(|a| db.relations[&a.relation].merges());
+            r.body.negated.is_empty() && !r.is_aggregate() && !merged
+        });
+        if !monotonic {
+            // anything the stratum derived could change
+            stratum.dirty = true;
+            for atom in rules.iter().flat_map(|r| &r.head) {
+                let rows = &db.relations[&atom.relation].rows;
+                deleted
+                    .entry(atom.relation)
+                    .or_default()
+                    .extend(rows.iter().map(<[Value]>::to_vec));
+            }
+            return;
+        }
+
+        let mut frontier = deleted.clone();
+        while !frontier.is_empty() {
+            let mut found = Tuples::default();
+            // a rule without positive atoms can't lose a derivation here
+            let handles = compiled
+                .iter()
+                .filter(|(r, _)| !r.body.atoms.is_empty())
+                .flat_map(|(r, hs)| hs.iter().map(move |h| (r, *h)));
+            for (rule, handle) in handles {
+                let mut substs = Substs::default();
+                db.eval_query_with_tuples(handle, &frontier, |s| substs.push(s));
+                for atom in &rule.head {
+                    let rel = &db.relations[&atom.relation];
+                    let tuples = project(db, handle, atom, &substs);
+                    for tuple in tuples.chunks_exact(rel.arity) {
+                        let lost = deleted.entry(atom.relation).or_default();
+                        if rel.rows.contains(tuple) && lost.insert(tuple.to_vec()) {
+                            found
+                                .entry(atom.relation)
+                                .or_default()
+                                .insert(tuple.to_vec());
+                        }
+                    }
+                }
+            }
+            frontier = found;
+        }
+    }
+
+    /// Puts back the deleted tuples of a stratum's relations that are still
+    /// base facts or still have a derivation.
+    fn rederive(&mut self, stratum: usize, deleted: &Tuples) {
+        if self.strata[stratum].dirty {
+            return;
+        }
+        let mut derived = vec![];
+        for r in self.strata[stratum].rules.clone() {
+            let rule = &self.rules[r].0;
+            if !rule.head.iter().any(|a| deleted.contains_key(&a.relation)) {
+                continue;
+            }
+            if !self.rederive.contains_key(&r) {
+                // the body joined with the head, which reads the deleted tuples
+                let body = self.expand_equivalences(&rule.body);
+                let db = &mut self.db;
+                let handles = rule
+                    .head
+                    .iter()
+                    .map(|head| {
+                        let mut query = body.clone();
+                        query.atoms.push(head.clone());
+                        let mut versions = vec![Version::All; query.atoms.len()];
+                        versions[query.atoms.len() - 1] = Version::Recent;
+                        db.add_versioned_query(query, versions)
+                    })
+                    .collect();
+                self.rederive.insert(r, handles);
+            }
+
+            let rule = &self.rules[r].0;
+            for (atom, &handle) in rule.head.iter().zip(&self.rederive[&r]) {
+                let mut substs = Substs::default();
+                let push = |s: &[Value]| substs.push(s);
+                self.db.eval_query_with_tuples(handle, deleted, push);
+                let tuples = project(&self.db, handle, atom, &substs);
+                let premises = match self.provenance {
+                    Some(_) => provenance::premises(&self.db, handle, rule, atom, &substs),
+                    None => vec![],
+                };
+                derived.push((r, atom.relation, tuples, premises));
+            }
+        }
+
+        for (r, sym, tuples, premises) in derived {
+            let provenance = &mut self.provenance;
+            insert_derived(&mut self.db, provenance, r, sym, &tuples, premises);
+        }
+        for (sym, tuples) in deleted {
+            if let Some(base) = self.base_facts.get(sym) {
+                let rel = self.db.relations.get_mut(sym).unwrap();
+                for tuple in tuples.iter().filter(|t| base.contains(*t)) {
+                    rel.insert(tuple);
+                }
+            }
+        }
     }
 
-    pub fn add_relation(&mut self, relation: Relation) {
+    /// Makes the last column of a relation hold elements of a lattice, see
+    /// [`db::Relation::set_lattice`]. Tuples derived from a superseded tuple
+    /// are kept, since they still hold for a smaller lattice element.
+    pub fn set_lattice(&mut self, relation: Symbol, lattice: impl db::Lattice) {
+        let rel = self.db.relations.get_mut(&relation).unwrap();
+        rel.set_lattice(lattice);
+        // the existing tuples were merged, so start over
+        for stratum in &mut self.strata {
+            stratum.dirty = true;
+        }
+    }
+
+    /// Adds a relation. If it declares a key, tuples that only differ in
+    /// the other columns are merged. Equivalence relations are stored as
+    /// union-find classes, see [`db::Relation::set_equivalence`].
+    ///
+    /// Fails if a key column is not in the schema, or if an equivalence
+    /// relation has a key or does not have two columns.
+    pub fn add_relation(&mut self, relation: Relation) -> Result<(), Error> {
         let symbol = relation.symbol;
+        if relation.equivalence && (relation.schema.len() != 2 || relation.key.is_some()) {
+            return Err(Error::InvalidEquivalence(symbol));
+        }
+        let key = match &relation.key {
+            Some(key) => {
+                let columns = key.columns.iter().map(|&column| {
+                    let index = relation.schema.index_of(column);
+                    index.ok_or(Error::UnknownColumn {
+                        relation: symbol,
+                        column,
+                    })
+                });
+                Some((columns.collect::<Result<Vec<_>, _>>()?, key.merge))
+            }
+            None => None,
+        };
+
         let arity = relation.schema.len();
-        self.db
+        let rel = self
+            .db
             .relations
             .entry(symbol)
             .and_modify(|_| panic!("a relation was already here"))
             .or_insert(db::Relation::new(arity));
+        rel.schema = Some(relation.schema);
+        match key {
+            Some((columns, Merge::Min)) => rel.set_key(&columns, db::Min),
+            Some((columns, Merge::Max)) => rel.set_key(&columns, db::Max),
+            None => (),
+        }
+        if relation.equivalence {
+            rel.set_equivalence();
+        }
+        Ok(())
     }
 
-    pub fn eval(&mut self, prog: Program) {
+    /// Adds a program's relations, rules, and facts, runs it, checks its
+    /// directives, and returns the answers to each of its queries. Fails on
+    /// the first `.assert` whose relations have different tuples.
+    ///
+    /// A program that only asks queries is not run in full. Its queries are
+    /// answered as if magic sets were on, even if they are off, see
+    /// [`query`](Self::query).
+    pub fn eval(&mut self, prog: Program) -> Result<Vec<Vec<Bindings>>, Error> {
         for rel in prog.relations {
-            self.add_relation(rel);
+            self.add_relation(rel)?;
         }
         for rule in prog.rules {
-            self.add_rule(rule);
+            self.add_rule(rule)?;
         }
         for fact in prog.facts {
             self.add_fact(&fact);
         }
 
-        self.run();
+        let query_only = !prog.queries.is_empty() && prog.directives.is_empty();
+        if !query_only {
+            self.run().fixpoint()?;
+        }
 
         for dir in prog.directives {
             match dir {
                 Directive::AssertEq(a, b) => {
-                    let a = &self.db.relations[&a].set;
-                    let b = &self.db.relations[&b].set;
-                    assert_eq!(a, b)
+                    let tuples = |sym| {
+                        let mut set = IndexSet::default();
+                        self.for_each(sym, |tuple| {
+                            set.insert(tuple.to_vec());
+                        });
+                        set
+                    };
+                    if tuples(a) != tuples(b) {
+                        return Err(Error::AssertionFailed(a, b));
+                    }
                 }
             }
         }
+        let magic_sets = self.magic_sets || query_only;
+        let queries = prog.queries.into_iter();
+        let queries = queries.map(|q| self.query_with(q, magic_sets));
+        queries.collect()
     }
 
-    pub fn parse_and_eval(&mut self, s: &str) {
+    pub fn parse_and_eval(&mut self, s: &str) -> Result<Vec<Vec<Bindings>>, Error> {
         let parser = parse::ProgramParser::new();
-        let prog = parser.parse(s).unwrap();
+        let prog = parser.parse(s).map_err(|e| Error::Parse(e.to_string()))?;
         self.eval(prog)
     }
 
     pub fn insert_many(&mut self, relation: Symbol, tuples: &[Value]) {
         let rel = self.db.relations.get_mut(&relation).unwrap();
-        rel.insert_many(tuples)
+        rel.insert_many(tuples);
+        if let Some(base) = self.base_facts.get_mut(&relation) {
+            base.extend(tuples.chunks_exact(rel.arity).map(|t| t.to_vec()));
+        }
+    }
+
+    /// The relations, for example to [`save`](db::Database::save) them once
+    /// the context reaches a fixpoint.
+    pub fn db(&self) -> &db::Database {
+        &self.db
+    }
+
+    /// Picks up where a context that ran `program` left off, from the
+    /// relations it saved, so it can be queried and given more facts and
+    /// rules that run incrementally. The saved tuples of relations that
+    /// rules derive into count as derived, so the database should be at a
+    /// fixpoint of the rules: the strata start out having seen every tuple.
+    /// The program's relations, rules, and facts are added, but it isn't
+    /// run, and its queries and directives are ignored.
+    ///
+    /// Fails like [`eval`](Self::eval), or if a relation was saved with
+    /// other columns than the program declares.
+    pub fn from_db(mut db: db::Database, program: Program) -> Result<Self, Error> {
+        let mut ctx = Self::default();
+        let mut saved = vec![];
+        for relation in program.relations {
+            let symbol = relation.symbol;
+            let schema = relation.schema.clone();
+            ctx.add_relation(relation)?;
+            if let Some(rel) = db.relations.shift_remove(&symbol) {
+                let same = match &rel.schema {
+                    Some(saved) => *saved == schema,
+                    None => rel.arity == schema.len(),
+                };
+                if !same {
+                    return Err(Error::SchemaMismatch(symbol));
+                }
+                saved.push((symbol, rel));
+            }
+        }
+        // relations that were not declared are kept as they are
+        ctx.db.relations.extend(db.relations.drain(..));
+        for rule in program.rules {
+            ctx.add_rule(rule)?;
+        }
+
+        // the tuples go in after the rules, so they are not base facts
+        for (symbol, saved) in saved {
+            let rel = ctx.db.relations.get_mut(&symbol).unwrap();
+            for tuple in saved.rows.iter() {
+                rel.insert(tuple);
+            }
+            rel.compact();
+        }
+        let Self {
+            db, rules, strata, ..
+        } = &mut ctx;
+        for stratum in strata {
+            for &r in &stratum.rules {
+                let body = &rules[r].0.body;
+                for atom in body.atoms.iter().chain(&body.negated) {
+                    let len = db.relations[&atom.relation].rows.len();
+                    stratum.seen.insert(atom.relation, len);
+                }
+            }
+            stratum.dirty = false;
+            stratum.started = true;
+        }
+        for fact in &program.facts {
+            ctx.add_fact(fact);
+        }
+        Ok(ctx)
+    }
+
+    /// Loads a database written by [`save`](db::Database::save) into a
+    /// context for `program`, see [`from_db`](Self::from_db).
+    pub fn load(path: impl AsRef<Path>, program: &str) -> Result<Self, Error> {
+        let parser = parse::ProgramParser::new();
+        let program = parser
+            .parse(program)
+            .map_err(|e| Error::Parse(e.to_string()))?;
+        Self::from_db(db::Database::load(path)?, program)
     }
 
     pub fn for_each(&self, relation: Symbol, mut f: impl FnMut(&[Value])) {
         let rel = self.db.relations.get(&relation).unwrap();
-        rel.set.iter().for_each(|tuple| f(tuple.as_ref()))
+        rel.for_each(|tuple| f(tuple))
     }
 
     pub fn collect<const N: usize>(&self, relation: Symbol) -> Vec<[Value; N]> {
@@ -83,62 +756,416 @@ impl DatalogContext {
         vec
     }
 
-    pub fn run(&mut self) -> usize {
-        let mut additions = 0;
-        loop {
-            let new = self.step();
-            if new == 0 {
-                return additions;
+    /// Runs each stratum to a fixpoint in order, returns how many tuples it
+    /// added and whether it got there. Non-recursive strata only read
+    /// relations computed by earlier strata, so they are only run once.
+    ///
+    /// Strata remember which tuples they have seen, so running again after
+    /// inserting new tuples only derives their consequences. Negation and
+    /// aggregates are not monotonic, so a stratum that negates or aggregates
+    /// over a relation that changed is recomputed from its base facts, and so
+    /// is every stratum that reads a recomputed relation.
+    ///
+    /// A run stops early when it hits a limit of its [`Budget`] or is
+    /// cancelled. Only whole iterations are applied and the strata remember
+    /// how far they got, so the next run picks up where this one stopped.
+    pub fn run(&mut self) -> Outcome {
+        let mut meter = Meter::new(self.budget, self.cancellation.clone());
+        let result = self.run_metered(&mut meter);
+        meter.outcome(result.err())
+    }
+
+    fn run_metered(&mut self, meter: &mut Meter) -> Result<(), Limit> {
+        self.compact();
+        let mut cleared = HashSet::default();
+        for i in 0..self.strata.len() {
+            if let Err(limit) = self.run_stratum(i, &mut cleared, meter) {
+                // the strata that did not run yet won't know what was cleared
+                for j in i + 1..self.strata.len() {
+                    if self.needs_recompute(j, &cleared) {
+                        self.strata[j].dirty = true;
+                    }
+                }
+                return Err(limit);
             }
-            additions += new;
         }
+        Ok(())
     }
 
-    pub fn step(&mut self) -> usize {
-        let all_substs: Vec<Vec<_>> = self
-            .rules
-            .iter()
-            .map(|(_r, qh)| {
-                let mut vec = Vec::new();
-                self.db.eval_query(*qh, |vals| vec.extend_from_slice(vals));
-                vec
+    fn run_stratum(
+        &mut self,
+        stratum: usize,
+        cleared: &mut HashSet<Symbol>,
+        meter: &mut Meter,
+    ) -> Result<(), Limit> {
+        if self.needs_recompute(stratum, cleared) {
+            self.clear_stratum(stratum, cleared);
+        }
+
+        let mut result = self.step(stratum, meter);
+        if self.strata[stratum].recursive {
+            while let Ok(1..) = result {
+                result = self.step(stratum, meter);
+            }
+        }
+        self.compact();
+        result.map(|_| ())
+    }
+
+    /// Removes the tuples of lattice relations that were superseded by a
+    /// joined value. Freeing their rows moves the rows after them, so the
+    /// rows each stratum has seen are recounted.
+    fn compact(&mut self) {
+        for (&sym, rel) in &mut self.db.relations {
+            if let Some(provenance) = &mut self.provenance {
+                let superseded = rel.superseded().map(|t| t.to_vec()).collect();
+                provenance.remove(sym, &superseded);
+            }
+            let rows = rel.compact();
+            if rows.is_empty() {
+                continue;
+            }
+            for stratum in &mut self.strata {
+                if let Some(seen) = stratum.seen.get_mut(&sym) {
+                    *seen -= rows.iter().filter(|&&row| row < *seen).count();
+                }
+            }
+        }
+    }
+
+    fn needs_recompute(&self, stratum: usize, cleared: &HashSet<Symbol>) -> bool {
+        let stratum = &self.strata[stratum];
+        let changed = |sym: &Symbol| match stratum.seen.get(sym) {
+            Some(&seen) => seen < self.db.relations[sym].rows.len(),
+            None => false,
+        };
+        stratum.dirty
+            || stratum.rules.iter().any(|&r| {
+                let rule = &self.rules[r].0;
+                let body = &rule.body;
+                let aggregated: &[Atom] = match rule.is_aggregate() {
+                    true => &body.atoms,
+                    false => &[],
+                };
+                let mut atoms = body.atoms.iter().chain(&body.negated);
+                let mut non_monotonic = body.negated.iter().chain(aggregated);
+                atoms.any(|a| cleared.contains(&a.relation))
+                    || non_monotonic.any(|a| changed(&a.relation))
             })
-            .collect();
+    }
 
-        let mut additions = 0;
-        for ((rule, handle), substs) in self.rules.iter().zip(all_substs) {
-            // get the vars, we only handle vars (no expressions) for now
-            assert_eq!(rule.head.len(), 1);
-            let atom = &rule.head[0];
-            let mut vars = Vec::with_capacity(atom.terms.len());
+    /// Resets the relations a stratum derives to their base facts.
+    fn clear_stratum(&mut self, stratum: usize, cleared: &mut HashSet<Symbol>) {
+        let stratum = &mut self.strata[stratum];
+        for &r in &stratum.rules {
+            for atom in &self.rules[r].0.head {
+                if cleared.insert(atom.relation) {
+                    if let Some(provenance) = &mut self.provenance {
+                        provenance.clear(atom.relation);
+                    }
+                    let rel = self.db.relations.get_mut(&atom.relation).unwrap();
+                    rel.clear();
+                    for tuple in &self.base_facts[&atom.relation] {
+                        rel.insert(tuple);
+                    }
+                }
+            }
+        }
+        stratum.seen.clear();
+        stratum.dirty = false;
+        stratum.started = false;
+    }
+
+    /// Replaces each atom over an equivalence relation by two atoms that
+    /// join its terms through the representative of their class, since only
+    /// the tuples from elements to their representatives are stored.
+    fn expand_equivalences(&self, query: &Query) -> Query {
+        let mut atoms = vec![];
+        for (i, atom) in query.atoms.iter().enumerate() {
+            if !self.db.relations[&atom.relation].is_equivalence() {
+                atoms.push(atom.clone());
+                continue;
+            }
+            let root = Term::Variable(Symbol::new(format!("_#class{}", i)));
             for term in &atom.terms {
-                match term {
-                    Term::Variable(v) => vars.push(*v),
-                    Term::Value(_) => panic!(),
+                atoms.push(Atom {
+                    relation: atom.relation,
+                    terms: vec![term.clone(), root.clone()],
+                });
+            }
+        }
+        Query {
+            atoms,
+            negated: query.negated.clone(),
+        }
+    }
+
+    /// Runs one semi-naive iteration of a stratum: the tuples it has not seen
+    /// yet are the recent tuples, and the delta queries of its rules are
+    /// evaluated against them. Returns the number of tuples added, or the
+    /// limit that stops the iteration, before it starts or while it joins.
+    /// A stopped iteration changes nothing, so it runs again next time.
+    ///
+    /// Rules only read the database until all of them are evaluated, so they
+    /// run in parallel. Their results are then inserted in rule order, which
+    /// keeps the order of the tuples deterministic.
+    fn step(&mut self, stratum: usize, meter: &mut Meter) -> Result<usize, Limit> {
+        let Self {
+            db,
+            rules,
+            strata,
+            pool,
+            provenance,
+            ..
+        } = self;
+        let stratum = &mut strata[stratum];
+        let mut deltas = Deltas::default();
+        for &r in &stratum.rules {
+            for atom in &rules[r].0.body.atoms {
+                let sym = atom.relation;
+                let len = db.relations[&sym].rows.len();
+                let seen = stratum.seen.get(&sym).copied().unwrap_or(0);
+                if seen < len {
+                    deltas.insert(sym, seen..len);
                 }
             }
+        }
+        // rules that derive tuples from no tuples fire once when it starts
+        let constant = |&r: &usize| {
+            let rule = &rules[r].0;
+            rule.body.atoms.is_empty() || rule.head.iter().any(Atom::has_empty_group)
+        };
+        let fire_constant = !stratum.started && stratum.rules.iter().any(constant);
+        if deltas.is_empty() && !fire_constant {
+            return Ok(0);
+        }
+        meter.check()?;
+
+        for &r in &stratum.rules {
+            for &handle in &rules[r].1 {
+                db.refresh_plan(handle, &deltas);
+            }
+        }
+
+        // for each rule, the tuples derived for each head atom, and their
+        // premises if provenance is on
+        let record = provenance.is_some();
+        // the substitutions produced so far, counted in batches
+        let produced = AtomicUsize::new(0);
+        // `is_multiple_of` needs a newer compiler than the crate supports
+        #[allow(clippy::manual_is_multiple_of)]
+        let push = |substs: &mut Substs, subst: &[Value]| {
+            substs.push(subst);
+            if substs.count % CHECK_EVERY != 0 {
+                return ControlFlow::Continue(());
+            }
+            let produced = produced.fetch_add(CHECK_EVERY, atomic::Ordering::Relaxed) + CHECK_EVERY;
+            match meter.interrupted(produced) {
+                Some(limit) => ControlFlow::Break(limit),
+                None => ControlFlow::Continue(()),
+            }
+        };
+        let eval_rule = |&r: &usize| -> Result<Vec<HeadTuples>, Limit> {
+            let (rule, handles) = &rules[r];
+            let mut atoms = rule.body.atoms.iter();
+            let fire = fire_constant && constant(&r);
+            if rule.is_aggregate() && !atoms.any(|a| deltas.contains_key(&a.relation)) && !fire {
+                return Ok(vec![]);
+            }
+            if rule.body.atoms.is_empty() && !fire_constant {
+                return Ok(vec![]);
+            }
+            let all_substs: Vec<Substs> = handles
+                .par_iter()
+                .map(|qh| match db.try_par_eval_query(*qh, &deltas, push) {
+                    ControlFlow::Continue(parts) => {
+                        let mut substs = Substs::default();
+                        for part in parts {
+                            substs.append(part);
+                        }
+                        Ok(substs)
+                    }
+                    ControlFlow::Break(limit) => Err(limit),
+                })
+                .collect::<Result<_, _>>()?;
+            // the body is evaluated once and shared by all the heads
+            let heads = rule
+                .head
+                .iter()
+                .map(|atom| {
+                    let mut tuples = vec![];
+                    let mut premises = vec![];
+                    for (qh, substs) in handles.iter().zip(&all_substs) {
+                        tuples.extend(project(db, *qh, atom, substs));
+                        if record {
+                            premises.extend(provenance::premises(db, *qh, rule, atom, substs));
+                        }
+                    }
+                    (tuples, premises)
+                })
+                .collect();
+            Ok(heads)
+        };
+        let eval_all = || stratum.rules.par_iter().map(eval_rule).collect();
+        let derived: Result<Vec<Vec<HeadTuples>>, Limit> = match pool {
+            Some(pool) => pool.install(eval_all),
+            None => eval_all(),
+        };
+        // an iteration stopped halfway is thrown away, and done again by the
+        // next run
+        let derived = derived.map_err(|limit| meter.stopped(limit))?;
+        stratum.started = true;
+
+        for (sym, recent) in deltas {
+            stratum.seen.insert(sym, recent.end);
+        }
+        // remember how much of the negated and aggregated relations this
+        // stratum saw, even if they had no recent rows
+        for &r in &stratum.rules {
+            let body = &rules[r].0.body;
+            let aggregated: &[Atom] = match rules[r].0.is_aggregate() {
+                true => &body.atoms,
+                false => &[],
+            };
+            for atom in body.negated.iter().chain(aggregated) {
+                let len = db.relations[&atom.relation].rows.len();
+                stratum.seen.insert(atom.relation, len);
+            }
+        }
+
+        let mut additions = 0;
+        for (&r, heads) in stratum.rules.iter().zip(derived) {
+            for (atom, (tuples, premises)) in rules[r].0.head.iter().zip(heads) {
+                additions += insert_derived(db, provenance, r, atom.relation, &tuples, premises);
+            }
+        }
+        meter.record(additions);
+
+        Ok(additions)
+    }
+}
+
+/// Inserts the tuples a rule derived, and records the derivations of the new
+/// ones if there are premises for them. Returns the number of tuples added.
+fn insert_derived(
+    db: &mut db::Database,
+    provenance: &mut Option<Provenance>,
+    rule: usize,
+    relation: Symbol,
+    tuples: &[Value],
+    premises: Vec<Premises>,
+) -> usize {
+    let rel = db.relations.get_mut(&relation).unwrap();
+    let mut premises = premises.into_iter();
+    let mut additions = 0;
+    for tuple in tuples.chunks_exact(rel.arity) {
+        let premises = premises.next();
+        if rel.insert(tuple) {
+            additions += 1;
+            if let (Some(provenance), Some(premises)) = (provenance.as_mut(), premises) {
+                provenance.record(relation, tuple, Derivation { rule, premises });
+            }
+        }
+    }
+    additions
+}
+
+/// The tuples a rule derived for a head atom, and their premises if
+/// provenance is on.
+type HeadTuples = (Vec<Value>, Vec<Premises>);
 
-            let idxs = self.db.get_indexes(*handle, &vars);
-            let subst_len = self.db.get_subst_len(*handle);
+/// How many substitutions a join produces between checks of the budget.
+const CHECK_EVERY: usize = 1024;
 
-            let rel = self.db.relations.get_mut(&atom.relation).unwrap();
-            let initial_size = rel.len();
+/// The substitutions produced by a query, flattened into one buffer. They are
+/// counted separately since a query without variables has empty ones.
+#[derive(Default)]
+struct Substs {
+    count: usize,
+    values: Vec<Value>,
+}
 
-            println!("{:?}", vars);
-            println!("{:?}", idxs);
+impl Substs {
+    fn push(&mut self, subst: &[Value]) {
+        self.count += 1;
+        self.values.extend_from_slice(subst);
+    }
 
-            let mut tuple = vec![Value::default(); rel.arity];
+    fn append(&mut self, mut other: Substs) {
+        self.count += other.count;
+        self.values.append(&mut other.values);
+    }
+
+    fn iter(&self, subst_len: usize) -> impl Iterator<Item = &[Value]> + '_ {
+        (0..self.count).map(move |i| &self.values[i * subst_len..(i + 1) * subst_len])
+    }
+}
 
-            for subst in substs.chunks_exact(subst_len) {
-                for (i, idx) in idxs.iter().enumerate() {
-                    tuple[i] = subst[*idx];
+/// Projects the substitutions of a query onto a head atom. If the head has
+/// aggregates, the substitutions are grouped by the head's variables first.
+fn project(db: &db::Database, handle: QueryHandle, atom: &Atom, substs: &Substs) -> Vec<Value> {
+    let subst_len = db.get_subst_len(handle);
+    let index = |v: &Variable| db.get_indexes(handle, &[*v])[0];
+
+    if !atom.has_aggregate() {
+        // constant columns are filled from the head itself
+        let idxs: Vec<Option<usize>> = atom
+            .terms
+            .iter()
+            .map(|term| match term {
+                Term::Variable(v) => Some(index(v)),
+                _ => None,
+            })
+            .collect();
+        let mut tuples = Vec::with_capacity(substs.count * idxs.len());
+        for subst in substs.iter(subst_len) {
+            for (term, idx) in atom.terms.iter().zip(&idxs) {
+                match idx {
+                    Some(i) => tuples.push(subst[*i]),
+                    None => tuples.push(term.eval()),
                 }
-                rel.insert(&tuple)
             }
+        }
+        return tuples;
+    }
 
-            additions += rel.len() - initial_size;
+    let mut keys = vec![];
+    let mut aggs = vec![];
+    for term in &atom.terms {
+        match term {
+            Term::Variable(v) => keys.push(index(v)),
+            Term::Aggregate(agg, v) => aggs.push((*agg, index(v))),
+            Term::Value(_) => (),
         }
+    }
 
-        additions
+    // for each group, the values of each aggregated variable
+    let mut groups: IndexMap<Vec<Value>, Vec<Vec<Value>>> = IndexMap::default();
+    for subst in substs.iter(subst_len) {
+        let key = keys.iter().map(|&i| subst[i]).collect();
+        let group = groups
+            .entry(key)
+            .or_insert_with(|| vec![vec![]; aggs.len()]);
+        for (values, &(_agg, i)) in group.iter_mut().zip(&aggs) {
+            values.push(subst[i]);
+        }
+    }
+    // so that counting nothing gives 0
+    if groups.is_empty() && atom.has_empty_group() {
+        groups.insert(vec![], vec![vec![]; aggs.len()]);
+    }
+
+    let mut tuples = vec![];
+    for (key, values) in groups {
+        let mut key = key.into_iter();
+        let mut aggregated = aggs.iter().zip(&values).map(|((agg, _), vs)| agg.apply(vs));
+        for term in &atom.terms {
+            match term {
+                Term::Variable(_) => tuples.push(key.next().unwrap()),
+                Term::Value(val) => tuples.push(*val),
+                Term::Aggregate(..) => tuples.push(aggregated.next().unwrap()),
+            }
+        }
     }
+    tuples
 }
@@ -0,0 +1,285 @@
+use crate::ast::{Atom, Query, Rule, Term, Variable};
+use crate::util::{HashSet, IndexMap, IndexSet, Symbol};
+
+/// A program rewritten with magic sets to answer one goal.
+pub(crate) struct MagicProgram {
+    pub rules: Vec<Rule>,
+    // the seed of the goal's magic relation
+    pub facts: Vec<Atom>,
+    // each adorned relation with the relation it stands for
+    pub adorned: IndexMap<Symbol, Symbol>,
+    // each magic relation with its arity
+    pub magic: IndexMap<Symbol, usize>,
+    // the adorned relation that answers the goal
+    pub goal: Symbol,
+}
+
+// which columns of an adorned relation are bound, as in `bf`
+type Adornment = Vec<bool>;
+
+/// Rewrites the rules that a goal depends on, so they only derive tuples
+/// that can contribute to it. Each relation is specialized for the columns
+/// that are bound when it is read, going left to right through rule bodies,
+/// and a magic relation collects the bound values it is read with. An
+/// adorned rule only fires for the values in its head's magic relation,
+/// and it derives the magic tuples of the atoms in its body from the atoms
+/// before them.
+///
+/// Only the rules of relations the goal depends on are kept. Of those,
+/// relations that are negated or aggregated over, that merge their tuples,
+/// or that share a rule with other heads are left alone, along with what
+/// they depend on, and their original rules are kept. Returns `None` if the
+/// goal's relation is one of those, is not derived, or has no bound column.
+pub(crate) fn transform(
+    rules: &[&Rule],
+    goal: &Atom,
+    merges: impl Fn(Symbol) -> bool,
+) -> Option<MagicProgram> {
+    // only the rules the goal depends on
+    let mut relevant: IndexSet<Symbol> = IndexSet::default();
+    relevant.insert(goal.relation);
+    let mut i = 0;
+    while let Some(&sym) = relevant.get_index(i) {
+        for rule in rules
+            .iter()
+            .filter(|r| r.head.iter().any(|a| a.relation == sym))
+        {
+            let body = rule.body.atoms.iter().chain(&rule.body.negated);
+            relevant.extend(rule.head.iter().chain(body).map(|a| a.relation));
+        }
+        i += 1;
+    }
+    let rules: Vec<&Rule> = rules
+        .iter()
+        .copied()
+        .filter(|r| r.head.iter().any(|a| relevant.contains(&a.relation)))
+        .collect();
+
+    let derived: IndexSet<Symbol> = rules
+        .iter()
+        .flat_map(|r| r.head.iter().map(|a| a.relation))
+        .collect();
+
+    // relations that have to be computed in full
+    let mut full: IndexSet<Symbol> = IndexSet::default();
+    for rule in &rules {
+        full.extend(rule.body.negated.iter().map(|a| a.relation));
+        if rule.is_aggregate() || rule.head.len() > 1 {
+            full.extend(rule.head.iter().map(|a| a.relation));
+        }
+    }
+    full.extend(derived.iter().copied().filter(|&sym| merges(sym)));
+    let mut i = 0;
+    while let Some(&sym) = full.get_index(i) {
+        for rule in rules
+            .iter()
+            .filter(|r| r.head.iter().any(|a| a.relation == sym))
+        {
+            let body = rule.body.atoms.iter().chain(&rule.body.negated);
+            full.extend(body.map(|a| a.relation));
+        }
+        i += 1;
+    }
+
+    let adornment: Adornment = goal
+        .terms
+        .iter()
+        .map(|t| matches!(t, Term::Value(_)))
+        .collect();
+    if !derived.contains(&goal.relation)
+        || full.contains(&goal.relation)
+        || !adornment.contains(&true)
+    {
+        return None;
+    }
+
+    let mut program = MagicProgram {
+        rules: vec![],
+        facts: vec![],
+        adorned: IndexMap::default(),
+        magic: IndexMap::default(),
+        goal: adorned_name(goal.relation, &adornment),
+    };
+    let seed = bound_terms(&goal.terms, &adornment);
+    program
+        .facts
+        .push(magic_atom(goal.relation, &adornment, seed));
+
+    let mut todo = vec![(goal.relation, adornment)];
+    let mut done = HashSet::default();
+    while let Some((sym, adornment)) = todo.pop() {
+        let name = adorned_name(sym, &adornment);
+        if !done.insert(name) {
+            continue;
+        }
+        program.adorned.insert(name, sym);
+        let magic = magic_name(sym, &adornment);
+        let n_bound = adornment.iter().filter(|&&b| b).count();
+        if n_bound > 0 {
+            program.magic.insert(magic, n_bound);
+        }
+
+        // the tuples that were inserted into the relation directly
+        let arity = adornment.len();
+        let vars: Vec<Term> = (0..arity)
+            .map(|i| Term::Variable(Symbol::new(format!("_#{}", i))))
+            .collect();
+        let base = Atom {
+            relation: sym,
+            terms: vars.clone(),
+        };
+        program
+            .rules
+            .push(adorned_rule(sym, &adornment, vars, vec![base], vec![]));
+
+        let defining = rules.iter().filter(|r| r.head[0].relation == sym);
+        for rule in defining {
+            let head = &rule.head[0];
+            let mut bound: HashSet<Variable> = bound_terms(&head.terms, &adornment)
+                .iter()
+                .filter_map(|t| match t {
+                    Term::Variable(v) => Some(*v),
+                    _ => None,
+                })
+                .collect();
+
+            let mut body = vec![];
+            for atom in &rule.body.atoms {
+                if !derived.contains(&atom.relation) || full.contains(&atom.relation) {
+                    bound.extend(atom.vars());
+                    body.push(atom.clone());
+                    continue;
+                }
+                let atom_adornment: Adornment = atom
+                    .terms
+                    .iter()
+                    .map(|t| match t {
+                        Term::Variable(v) => bound.contains(v),
+                        _ => true,
+                    })
+                    .collect();
+
+                // the values this atom is read with come from the head's
+                // magic relation and the atoms before it
+                let terms = bound_terms(&atom.terms, &atom_adornment);
+                let magic_head = magic_atom(atom.relation, &atom_adornment, terms);
+                let magic_rule = adorned_body(sym, &adornment, &head.terms, body.clone());
+                match (magic_head.terms.is_empty(), magic_rule.is_empty()) {
+                    (true, _) => (),
+                    (false, true) => program.facts.push(magic_head),
+                    (false, false) => program.rules.push(Rule {
+                        head: vec![magic_head],
+                        body: Query {
+                            atoms: magic_rule,
+                            negated: vec![],
+                        },
+                    }),
+                }
+
+                bound.extend(atom.vars());
+                body.push(Atom {
+                    relation: adorned_name(atom.relation, &atom_adornment),
+                    terms: atom.terms.clone(),
+                });
+                todo.push((atom.relation, atom_adornment));
+            }
+
+            let negated = rule.body.negated.clone();
+            let rule = adorned_rule(sym, &adornment, head.terms.clone(), body, negated);
+            program.rules.push(rule);
+        }
+    }
+
+    // the relations left alone keep their rules
+    for rule in &rules {
+        if full.contains(&rule.head[0].relation) {
+            program.rules.push((*rule).clone());
+        }
+    }
+    Some(program)
+}
+
+fn adorned_name(sym: Symbol, adornment: &[bool]) -> Symbol {
+    let bf: String = adornment
+        .iter()
+        .map(|&b| if b { 'b' } else { 'f' })
+        .collect();
+    Symbol::new(format!("{}#{}", sym, bf))
+}
+
+fn magic_name(sym: Symbol, adornment: &[bool]) -> Symbol {
+    Symbol::new(format!("magic#{}", adorned_name(sym, adornment)))
+}
+
+fn bound_terms(terms: &[Term], adornment: &[bool]) -> Vec<Term> {
+    let terms = terms.iter().zip(adornment);
+    terms.filter(|(_, &b)| b).map(|(t, _)| t.clone()).collect()
+}
+
+fn magic_atom(sym: Symbol, adornment: &[bool], terms: Vec<Term>) -> Atom {
+    Atom {
+        relation: magic_name(sym, adornment),
+        terms,
+    }
+}
+
+// the body of an adorned rule: its head's magic atom, then the given atoms
+fn adorned_body(sym: Symbol, adornment: &[bool], head: &[Term], atoms: Vec<Atom>) -> Vec<Atom> {
+    let bound = bound_terms(head, adornment);
+    // a magic relation without columns always holds once it is reached
+    let magic = (!bound.is_empty()).then(|| magic_atom(sym, adornment, bound));
+    magic.into_iter().chain(atoms).collect()
+}
+
+fn adorned_rule(
+    sym: Symbol,
+    adornment: &[bool],
+    head: Vec<Term>,
+    atoms: Vec<Atom>,
+    negated: Vec<Atom>,
+) -> Rule {
+    let atoms = adorned_body(sym, adornment, &head, atoms);
+    Rule {
+        head: vec![Atom {
+            relation: adorned_name(sym, adornment),
+            terms: head,
+        }],
+        body: Query { atoms, negated },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{AtomParser, RuleParser};
+
+    #[test]
+    fn adorn_reach() {
+        let parser = RuleParser::new();
+        let rules: Vec<Rule> = [
+            "reach(x, y) :- edge(x, y)",
+            "reach(x, z) :- reach(x, y), edge(y, z)",
+            "far(x, z) :- reach(x, y), reach(y, z)",
+        ]
+        .iter()
+        .map(|r| parser.parse(r).unwrap())
+        .collect();
+        let rules: Vec<&Rule> = rules.iter().collect();
+        let goal = AtomParser::new().parse("far(1, x)").unwrap();
+
+        let program = transform(&rules, &goal, |_| false).unwrap();
+        let adorned: Vec<String> = program.adorned.keys().map(|s| s.to_string()).collect();
+        assert_eq!(adorned, ["far#bf", "reach#bf"]);
+        let magic: Vec<String> = program.magic.keys().map(|s| s.to_string()).collect();
+        assert_eq!(magic, ["magic#far#bf", "magic#reach#bf"]);
+        // far reads reach from 1 and from everything reach finds from 1
+        let magic_rules = program
+            .rules
+            .iter()
+            .filter(|r| r.head[0].relation.to_string() == "magic#reach#bf");
+        assert_eq!(magic_rules.count(), 3);
+
+        let goal = AtomParser::new().parse("far(x, y)").unwrap();
+        assert!(transform(&rules, &goal, |_| false).is_none());
+    }
+}
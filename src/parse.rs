@@ -17,6 +17,7 @@ mod tests {
         let p = IdentParser::new();
         assert_eq!(p.parse("_foo_123").unwrap(), Symbol::new("_foo_123"));
         assert_eq!(p.parse("_").unwrap(), Symbol::new("_"));
+        assert_eq!(p.parse("count").unwrap(), Symbol::new("count"));
         assert!(p.parse("0").is_err());
     }
 }
@@ -0,0 +1,185 @@
+use crate::ast::{Atom, Rule, Term, Value};
+use crate::db::{Database, QueryHandle};
+use crate::transaction::Transactional;
+use crate::util::{IndexMap, IndexSet, Symbol};
+use crate::Substs;
+
+// the tuple each positive body atom of a rule matched
+pub(crate) type Premises = Vec<(Symbol, Vec<Value>)>;
+
+/// How a tuple was first derived.
+#[derive(Debug, Clone)]
+pub(crate) struct Derivation {
+    pub rule: usize,
+    pub premises: Premises,
+}
+
+/// The derivation of every derived tuple, by relation. Tuples without one
+/// are base facts.
+#[derive(Default)]
+pub(crate) struct Provenance {
+    derivations: IndexMap<Symbol, IndexMap<Vec<Value>, Derivation>>,
+    // how to undo the changes made since the transaction began, if one is
+    // open, latest last
+    undo: Option<Vec<Undo>>,
+}
+
+// a change to the derivations, with what it took out
+enum Undo {
+    Recorded(Symbol, Vec<Value>),
+    Removed(Symbol, Vec<Value>, Derivation),
+    Cleared(Symbol, IndexMap<Vec<Value>, Derivation>),
+}
+
+impl Provenance {
+    /// Records a derivation, unless the tuple already has one.
+    pub fn record(&mut self, relation: Symbol, tuple: &[Value], derivation: Derivation) {
+        let derivations = self.derivations.entry(relation).or_default();
+        if !derivations.contains_key(tuple) {
+            derivations.insert(tuple.to_vec(), derivation);
+            if let Some(undo) = &mut self.undo {
+                undo.push(Undo::Recorded(relation, tuple.to_vec()));
+            }
+        }
+    }
+
+    pub fn clear(&mut self, relation: Symbol) {
+        let derivations = self.derivations.shift_remove(&relation);
+        if let (Some(undo), Some(derivations)) = (&mut self.undo, derivations) {
+            undo.push(Undo::Cleared(relation, derivations));
+        }
+    }
+
+    pub fn remove(&mut self, relation: Symbol, tuples: &IndexSet<Vec<Value>>) {
+        let Some(derivations) = self.derivations.get_mut(&relation) else {
+            return;
+        };
+        for tuple in tuples {
+            let derivation = derivations.swap_remove(tuple);
+            if let (Some(undo), Some(derivation)) = (&mut self.undo, derivation) {
+                undo.push(Undo::Removed(relation, tuple.clone(), derivation));
+            }
+        }
+    }
+
+    /// Starts logging changes, so they can be undone if the transaction
+    /// that just began is rolled back.
+    pub fn savepoint(&mut self) {
+        assert!(self.undo.is_none(), "A transaction is already open");
+        self.undo = Some(vec![]);
+    }
+
+    /// Rebuilds the proof of a tuple, down to base facts.
+    pub fn proof<'a, R>(&self, rules: &R, relation: Symbol, tuple: &[Value]) -> Proof<'a>
+    where
+        R: Fn(usize) -> &'a Rule,
+    {
+        let derivation = self.derivations.get(&relation).and_then(|ds| ds.get(tuple));
+        let (rule, premises) = match derivation {
+            Some(d) => {
+                let premises = d.premises.iter();
+                let premises = premises.map(|(sym, tuple)| self.proof(rules, *sym, tuple));
+                (Some(rules(d.rule)), premises.collect())
+            }
+            None => (None, vec![]),
+        };
+        Proof {
+            relation,
+            tuple: tuple.to_vec(),
+            rule,
+            premises,
+        }
+    }
+}
+
+impl Transactional for Provenance {
+    fn release(&mut self) {
+        self.undo = None;
+    }
+
+    fn restore(&mut self) {
+        for change in self.undo.take().into_iter().flatten().rev() {
+            match change {
+                Undo::Recorded(relation, tuple) => {
+                    if let Some(derivations) = self.derivations.get_mut(&relation) {
+                        derivations.swap_remove(&tuple);
+                    }
+                }
+                Undo::Removed(relation, tuple, derivation) => {
+                    let derivations = self.derivations.entry(relation).or_default();
+                    derivations.insert(tuple, derivation);
+                }
+                Undo::Cleared(relation, derivations) => {
+                    self.derivations.insert(relation, derivations);
+                }
+            }
+        }
+    }
+}
+
+/// Why a tuple is in a relation: the rule that first derived it, and the
+/// proofs of the tuples its body matched.
+#[derive(Debug, Clone)]
+pub struct Proof<'a> {
+    pub relation: Symbol,
+    pub tuple: Vec<Value>,
+    /// `None` if the tuple is a base fact.
+    pub rule: Option<&'a Rule>,
+    pub premises: Vec<Proof<'a>>,
+}
+
+/// The premises of each tuple [`project`](crate::project) makes from the
+/// same substitutions, in the same order. A tuple of an aggregate head is
+/// derived from every substitution in its group.
+pub(crate) fn premises(
+    db: &Database,
+    handle: QueryHandle,
+    rule: &Rule,
+    atom: &Atom,
+    substs: &Substs,
+) -> Vec<Premises> {
+    let subst_len = db.get_subst_len(handle);
+    let index = |t: &Term| match t {
+        Term::Variable(v) | Term::Aggregate(_, v) => Some(db.get_indexes(handle, &[*v])[0]),
+        Term::Value(_) => None,
+    };
+    let body: Vec<Vec<Option<usize>>> = rule
+        .body
+        .atoms
+        .iter()
+        .map(|a| a.terms.iter().map(index).collect())
+        .collect();
+    let instantiate = |subst: &[Value]| -> Premises {
+        let atoms = rule.body.atoms.iter().zip(&body);
+        atoms
+            .map(|(a, idxs)| {
+                let terms = a.terms.iter().zip(idxs);
+                let tuple = terms.map(|(term, idx)| match idx {
+                    Some(i) => subst[*i],
+                    None => term.eval(),
+                });
+                (a.relation, tuple.collect())
+            })
+            .collect()
+    };
+
+    if !atom.has_aggregate() {
+        return substs.iter(subst_len).map(instantiate).collect();
+    }
+
+    let keys: Vec<usize> = atom
+        .terms
+        .iter()
+        .filter(|t| matches!(t, Term::Variable(_)))
+        .filter_map(index)
+        .collect();
+    let mut groups: IndexMap<Vec<Value>, Premises> = IndexMap::default();
+    for subst in substs.iter(subst_len) {
+        let key = keys.iter().map(|&i| subst[i]).collect();
+        groups.entry(key).or_default().extend(instantiate(subst));
+    }
+    if groups.is_empty() && atom.has_empty_group() {
+        groups.insert(vec![], vec![]);
+    }
+    groups.into_values().collect()
+}
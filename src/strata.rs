@@ -0,0 +1,167 @@
+use crate::ast::{Atom, Rule};
+use crate::util::{IndexMap, IndexSet, Symbol};
+use crate::Error;
+
+#[derive(Default, Clone)]
+pub(crate) struct Stratum {
+    pub rules: Vec<usize>,
+    // non-recursive strata only need to be run once
+    pub recursive: bool,
+    // how many rows of each relation the rules have already been run on
+    pub seen: IndexMap<Symbol, usize>,
+    // the rules changed, so the stratum has to be recomputed from scratch
+    pub dirty: bool,
+    // rules without positive atoms have no new tuples to read, so they only
+    // fire in the first step after the stratum is cleared
+    pub started: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Dependency {
+    Positive,
+    Negative,
+    Aggregate,
+}
+
+// edges go from a relation to the relations it depends on
+type Graph = IndexMap<Symbol, Vec<(Symbol, Dependency)>>;
+
+/// Splits the predicate dependency graph of the rules into strongly
+/// connected components, and returns one stratum per component in
+/// topological order, so every stratum only depends on itself and the
+/// strata before it. Fails if a relation is negated or aggregated over
+/// inside its own component.
+pub(crate) fn stratify(rules: &[&Rule]) -> Result<Vec<Stratum>, Error> {
+    let mut graph = Graph::default();
+    for rule in rules {
+        for head in &rule.head {
+            let deps = graph.entry(head.relation).or_default();
+            let dep = match rule.is_aggregate() {
+                true => Dependency::Aggregate,
+                false => Dependency::Positive,
+            };
+            let positive = rule.body.atoms.iter().map(|a| (a.relation, dep));
+            let negative = rule.body.negated.iter();
+            let negative = negative.map(|a| (a.relation, Dependency::Negative));
+            deps.extend(positive.chain(negative));
+            // heads of the same rule are computed together
+            deps.extend(rule.head.iter().map(|a| (a.relation, Dependency::Positive)));
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        graph: &graph,
+        index: Default::default(),
+        lowlink: Default::default(),
+        stack: Default::default(),
+        components: vec![],
+    };
+    for &sym in graph.keys() {
+        if !tarjan.index.contains_key(&sym) {
+            tarjan.visit(sym);
+        }
+    }
+
+    let mut component_of = IndexMap::default();
+    for (i, component) in tarjan.components.iter().enumerate() {
+        for &sym in component {
+            component_of.insert(sym, i);
+        }
+    }
+
+    for (&sym, deps) in &graph {
+        let i = component_of[&sym];
+        for &(dep, kind) in deps {
+            if component_of.get(&dep) == Some(&i) {
+                match kind {
+                    Dependency::Positive => (),
+                    Dependency::Negative => return Err(Error::NegativeCycle(sym)),
+                    Dependency::Aggregate => return Err(Error::RecursiveAggregate(sym)),
+                }
+            }
+        }
+    }
+
+    let mut strata: Vec<Stratum> = tarjan
+        .components
+        .iter()
+        .map(|_| Stratum::default())
+        .collect();
+    for (r, rule) in rules.iter().enumerate() {
+        if let Some(head) = rule.head.first() {
+            let i = component_of[&head.relation];
+            let is_recursive = |a: &Atom| component_of.get(&a.relation) == Some(&i);
+            strata[i].rules.push(r);
+            strata[i].recursive |= tarjan.components[i].len() > 1;
+            strata[i].recursive |= rule.body.atoms.iter().any(is_recursive);
+        }
+    }
+    strata.retain(|stratum| !stratum.rules.is_empty());
+    Ok(strata)
+}
+
+struct Tarjan<'a> {
+    graph: &'a Graph,
+    index: IndexMap<Symbol, usize>,
+    lowlink: IndexMap<Symbol, usize>,
+    stack: IndexSet<Symbol>,
+    // components are found in reverse topological order of the edges, so
+    // dependencies come first
+    components: Vec<Vec<Symbol>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, sym: Symbol) {
+        let i = self.index.len();
+        self.index.insert(sym, i);
+        self.lowlink.insert(sym, i);
+        self.stack.insert(sym);
+
+        let deps = self.graph.get(&sym).map_or(&[][..], |deps| deps.as_slice());
+        for &(dep, _) in deps {
+            if !self.graph.contains_key(&dep) {
+                // not derived by any rule
+                continue;
+            }
+            if !self.index.contains_key(&dep) {
+                self.visit(dep);
+                self.lowlink[&sym] = self.lowlink[&sym].min(self.lowlink[&dep]);
+            } else if self.stack.contains(&dep) {
+                self.lowlink[&sym] = self.lowlink[&sym].min(self.index[&dep]);
+            }
+        }
+
+        if self.lowlink[&sym] == self.index[&sym] {
+            let start = self.stack.get_index_of(&sym).unwrap();
+            let component = self.stack.drain(start..).collect();
+            self.components.push(component);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::RuleParser;
+
+    #[test]
+    fn sccs_in_order() {
+        let parser = RuleParser::new();
+        let rules: Vec<Rule> = [
+            "c(x) :- b(x), !a(x)",
+            "b(x) :- a(x)",
+            "b(x) :- b(y), e(x, y)",
+            "a(x) :- n(x)",
+        ]
+        .iter()
+        .map(|r| parser.parse(r).unwrap())
+        .collect();
+        let rules: Vec<&Rule> = rules.iter().collect();
+
+        let strata = stratify(&rules).unwrap();
+        let order: Vec<_> = strata.iter().map(|s| s.rules.clone()).collect();
+        assert_eq!(order, vec![vec![3], vec![1, 2], vec![0]]);
+        let recursive: Vec<_> = strata.iter().map(|s| s.recursive).collect();
+        assert_eq!(recursive, vec![false, true, false]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::ast::Value;
+use crate::db::QueryHandle;
+use crate::provenance::Provenance;
+use crate::strata::Stratum;
+use crate::util::{IndexMap, IndexSet, Symbol};
+use crate::DatalogContext;
+
+/// What a context had when a transaction began, besides its database.
+pub(crate) struct Savepoint {
+    rules: usize,
+    strata: Vec<Stratum>,
+    rederive: IndexMap<usize, Vec<QueryHandle>>,
+    // the provenance from before it was turned on or off, otherwise it
+    // undoes its own changes
+    provenance: Option<Option<Provenance>>,
+    // the base facts only grow until one is retracted, then they are copied
+    base_lens: Vec<usize>,
+    base_facts: Option<IndexMap<Symbol, IndexSet<Vec<Value>>>>,
+}
+
+/// What a transaction can be begun on: the changes made since it began are
+/// either kept or undone.
+pub trait Transactional {
+    /// Keeps the changes made since the transaction began.
+    fn release(&mut self);
+
+    /// Undoes the changes made since the transaction began.
+    fn restore(&mut self);
+}
+
+/// Changes that are undone unless they are committed, see
+/// [`Database::begin`](crate::db::Database::begin) and
+/// [`DatalogContext::begin`]. It derefs to what it was begun on.
+pub struct Transaction<'a, T: Transactional> {
+    inner: &'a mut T,
+}
+
+impl<'a, T: Transactional> Transaction<'a, T> {
+    // the savepoint must already be taken
+    pub(crate) fn new(inner: &'a mut T) -> Self {
+        Self { inner }
+    }
+
+    pub fn commit(self) {
+        self.inner.release();
+        // there is nothing left to roll back
+        std::mem::forget(self);
+    }
+
+    pub fn rollback(self) {
+        // dropping rolls back
+    }
+}
+
+impl<T: Transactional> Drop for Transaction<'_, T> {
+    fn drop(&mut self) {
+        self.inner.restore();
+    }
+}
+
+impl<T: Transactional> Deref for Transaction<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner
+    }
+}
+
+impl<T: Transactional> DerefMut for Transaction<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner
+    }
+}
+
+impl DatalogContext {
+    /// Begins a transaction. Until it is committed, the facts, relations,
+    /// and rules added, the facts retracted, and everything runs derive can
+    /// be rolled back, and so is dropping it. The relations are restored
+    /// like in [`db::Database::begin`](crate::db::Database::begin), so a
+    /// speculative batch of facts costs about as much as what it derives.
+    ///
+    /// Panics if a transaction is already open.
+    pub fn begin(&mut self) -> Transaction<'_, Self> {
+        self.db.savepoint();
+        if let Some(provenance) = &mut self.provenance {
+            provenance.savepoint();
+        }
+        self.savepoint = Some(Savepoint {
+            rules: self.rules.len(),
+            strata: self.strata.clone(),
+            rederive: self.rederive.clone(),
+            provenance: None,
+            base_lens: self.base_facts.values().map(IndexSet::len).collect(),
+            base_facts: None,
+        });
+        Transaction::new(self)
+    }
+
+    /// Sets the provenance aside before it is turned on or off, if a
+    /// transaction is open.
+    pub(crate) fn modify_provenance(&mut self) {
+        if let Some(savepoint) = &mut self.savepoint {
+            if savepoint.provenance.is_none() {
+                let mut provenance = self.provenance.take();
+                if let Some(provenance) = &mut provenance {
+                    provenance.restore();
+                }
+                savepoint.provenance = Some(provenance);
+            }
+        }
+    }
+
+    /// Copies the base facts before one is removed, if a transaction is open.
+    pub(crate) fn modify_base_facts(&mut self) {
+        if let Some(savepoint) = &mut self.savepoint {
+            if savepoint.base_facts.is_none() {
+                let mut base_facts = self.base_facts.clone();
+                truncate(&mut base_facts, &savepoint.base_lens);
+                savepoint.base_facts = Some(base_facts);
+            }
+        }
+    }
+}
+
+impl Transactional for DatalogContext {
+    fn release(&mut self) {
+        self.db.release();
+        if let Some(provenance) = &mut self.provenance {
+            provenance.release();
+        }
+        self.savepoint = None;
+    }
+
+    fn restore(&mut self) {
+        let savepoint = self.savepoint.take().expect("No transaction is open");
+        self.db.restore();
+        self.rules.truncate(savepoint.rules);
+        self.strata = savepoint.strata;
+        self.rederive = savepoint.rederive;
+        match savepoint.provenance {
+            Some(provenance) => self.provenance = provenance,
+            None => {
+                if let Some(provenance) = &mut self.provenance {
+                    provenance.restore();
+                }
+            }
+        }
+        match savepoint.base_facts {
+            Some(base_facts) => self.base_facts = base_facts,
+            None => truncate(&mut self.base_facts, &savepoint.base_lens),
+        }
+    }
+}
+
+// cuts the base facts back to the relations and tuples they had
+fn truncate(base_facts: &mut IndexMap<Symbol, IndexSet<Vec<Value>>>, lens: &[usize]) {
+    base_facts.truncate(lens.len());
+    for (base, &len) in base_facts.values_mut().zip(lens) {
+        base.truncate(len);
+    }
+}
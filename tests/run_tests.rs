@@ -1,9 +1,14 @@
-use datastick::DatalogContext;
+use datastick::{
+    ast::{Atom, Term, Type, Value},
+    db::{Database, Min},
+    util::Symbol,
+    Budget, DatalogContext, Error, Limit,
+};
+use std::time::Duration;
 
 fn tests_in_dir(dir: &str) -> impl Iterator<Item = String> {
     std::fs::read_dir(dir)
         .unwrap()
-        .into_iter()
         .map(|entry| entry.unwrap().path())
         .filter(|path| path.is_file() && path.extension().unwrap_or_default() == "dl")
         .inspect(|path| println!("Test {:?}", path))
@@ -14,6 +19,770 @@ fn tests_in_dir(dir: &str) -> impl Iterator<Item = String> {
 fn test_passing() {
     for s in tests_in_dir("tests/pass") {
         let mut ctx = DatalogContext::default();
-        ctx.parse_and_eval(&s)
+        ctx.parse_and_eval(&s).unwrap();
     }
 }
+
+/// Each failing test starts with a `// error: Variant` comment naming the
+/// error it fails with.
+#[test]
+fn test_failing() {
+    for s in tests_in_dir("tests/fail") {
+        let expected = s
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("// error: "))
+            .expect("Failing tests start with the expected error");
+        let mut ctx = DatalogContext::default();
+        let err = ctx.parse_and_eval(&s).unwrap_err();
+        println!("Error: {}", err);
+        let debug = format!("{:?}", err);
+        let variant = debug.split(|c: char| !c.is_alphanumeric()).next();
+        assert_eq!(variant, Some(expected.trim()));
+    }
+}
+
+#[test]
+fn test_deterministic() {
+    let program = std::fs::read_to_string("tests/pass/reachable.dl").unwrap();
+    let reach = Symbol::new("reach");
+
+    let mut sequential = DatalogContext::default();
+    sequential.set_num_threads(1);
+    sequential.parse_and_eval(&program).unwrap();
+
+    let mut parallel = DatalogContext::default();
+    parallel.set_num_threads(4);
+    parallel.parse_and_eval(&program).unwrap();
+
+    assert_eq!(sequential.collect::<2>(reach), parallel.collect::<2>(reach));
+}
+
+#[test]
+fn test_incremental() {
+    let program = "
+        .decl edge(a: i32, b: i32).
+        .decl node(a: i32).
+        .decl reach(a: i32, b: i32).
+        .decl unreach(a: i32, b: i32).
+        .decl deg(a: i32, n: i32).
+
+        node(1). node(2). node(3).
+        edge(1, 2). edge(2, 3).
+
+        reach(a, b) :- edge(a, b).
+        reach(a, c) :- reach(a, b), edge(b, c).
+        unreach(a, b) :- node(a), node(b), !reach(a, b).
+        deg(a, count(b)) :- reach(a, b).
+    ";
+    let batches = ["edge(3, 4). node(4).", "edge(4, 1).", "reach(4, 4)."];
+
+    let mut incremental = DatalogContext::default();
+    incremental.parse_and_eval(program).unwrap();
+    for batch in &batches {
+        incremental.parse_and_eval(batch).unwrap();
+    }
+
+    let mut full = DatalogContext::default();
+    full.parse_and_eval(&(program.to_string() + &batches.concat()))
+        .unwrap();
+
+    for rel in &["reach", "unreach", "deg"] {
+        let rel = Symbol::new(*rel);
+        let mut expected = full.collect::<2>(rel);
+        let mut actual = incremental.collect::<2>(rel);
+        expected.sort();
+        actual.sort();
+        assert_eq!(expected, actual, "{} differs", rel);
+    }
+
+    // counting nothing gives 0 until there is something to count
+    let mut ctx = DatalogContext::default();
+    ctx.parse_and_eval(".decl e(a: i32). .decl n(c: i32). n(count(a)) :- e(a).")
+        .unwrap();
+    let (e, n) = (Symbol::new("e"), Symbol::new("n"));
+    assert_eq!(ctx.collect::<1>(n), [[0.to_value()]]);
+    ctx.insert_many(e, &[5.to_value(), 6.to_value()]);
+    ctx.run().fixpoint().unwrap();
+    assert_eq!(ctx.collect::<1>(n), [[2.to_value()]]);
+    ctx.retract_many(e, &[5.to_value(), 6.to_value()]).unwrap();
+    assert_eq!(ctx.collect::<1>(n), [[0.to_value()]]);
+}
+
+#[test]
+fn test_retract() {
+    let program = "
+        .decl edge(a: i32, b: i32).
+        .decl node(a: i32).
+        .decl reach(a: i32, b: i32).
+        .decl unreach(a: i32, b: i32).
+        .decl deg(a: i32, n: i32).
+
+        reach(a, b) :- edge(a, b).
+        reach(a, c) :- reach(a, b), edge(b, c).
+        unreach(a, b) :- node(a), node(b), !reach(a, b).
+        deg(a, count(b)) :- reach(a, b).
+    ";
+    let facts = [
+        "node(1).",
+        "node(2).",
+        "node(3).",
+        "node(4).",
+        "edge(1, 2).",
+        "edge(2, 3).",
+        "edge(3, 1).",
+        "edge(3, 4).",
+        "edge(1, 4).",
+        "reach(4, 4).",
+    ];
+    let edge = Symbol::new("edge");
+    let reach = Symbol::new("reach");
+    let retracted = [
+        (edge, [3, 1]),
+        (edge, [1, 4]),
+        (reach, [4, 4]),
+        (edge, [2, 3]),
+    ];
+
+    let mut incremental = DatalogContext::default();
+    incremental.parse_and_eval(program).unwrap();
+    incremental.parse_and_eval(&facts.concat()).unwrap();
+
+    for n in 1..=retracted.len() {
+        let (rel, tuple) = retracted[n - 1];
+        let tuple: Vec<Value> = tuple.iter().map(|&v| v.to_value()).collect();
+        incremental.retract_many(rel, &tuple).unwrap();
+
+        let removed: Vec<String> = retracted[..n]
+            .iter()
+            .map(|(rel, [a, b])| format!("{}({}, {}).", rel, a, b))
+            .collect();
+        let kept: String = facts
+            .iter()
+            .filter(|f| !removed.contains(&f.to_string()))
+            .cloned()
+            .collect();
+        let mut full = DatalogContext::default();
+        full.parse_and_eval(&(program.to_string() + &kept)).unwrap();
+
+        for rel in &["reach", "unreach", "deg"] {
+            let rel = Symbol::new(*rel);
+            let mut expected = full.collect::<2>(rel);
+            let mut actual = incremental.collect::<2>(rel);
+            expected.sort();
+            actual.sort();
+            assert_eq!(expected, actual, "{} differs after {} retractions", rel, n);
+        }
+    }
+
+    // equivalence classes can't be split, so retracting from one fails
+    let mut ctx = DatalogContext::default();
+    ctx.parse_and_eval(
+        "
+        .decl same(a: i32, b: i32) eqrel.
+        same(1, 2).
+        ",
+    )
+    .unwrap();
+    let same = Symbol::new("same");
+    let pair = [1.to_value(), 2.to_value()];
+    assert!(matches!(
+        ctx.retract_many(same, &pair),
+        Err(Error::RetractEquivalence(_))
+    ));
+    assert_eq!(ctx.collect::<2>(same).len(), 4);
+}
+
+#[test]
+fn test_provenance() {
+    let program = "
+        .decl edge(a: i32, b: i32).
+        .decl reach(a: i32, b: i32).
+        .decl deg(a: i32, n: i32).
+
+        edge(1, 2). edge(2, 3). edge(1, 3).
+
+        reach(a, b) :- edge(a, b).
+        reach(a, c) :- reach(a, b), edge(b, c).
+        deg(a, count(b)) :- reach(a, b).
+    ";
+    let (edge, reach, deg) = (
+        Symbol::new("edge"),
+        Symbol::new("reach"),
+        Symbol::new("deg"),
+    );
+    let tuple = |vals: &[i32]| -> Vec<Value> { vals.iter().map(|&v| v.to_value()).collect() };
+
+    let mut ctx = DatalogContext::default();
+    ctx.set_provenance(true);
+    ctx.parse_and_eval(program).unwrap();
+
+    let proof = ctx.explain(edge, &tuple(&[1, 2])).unwrap();
+    assert!(proof.rule.is_none() && proof.premises.is_empty());
+    assert!(ctx.explain(edge, &tuple(&[3, 1])).is_none());
+
+    // reach(1, 3) is first derived directly from edge(1, 3)
+    let proof = ctx.explain(reach, &tuple(&[1, 3])).unwrap();
+    assert_eq!(proof.rule.unwrap().body.atoms.len(), 1);
+    assert_eq!(proof.premises[0].relation, edge);
+    assert_eq!(proof.premises[0].tuple, tuple(&[1, 3]));
+
+    // without edge(1, 3), it is derived through reach(1, 2)
+    ctx.retract_many(edge, &tuple(&[1, 3])).unwrap();
+    let proof = ctx.explain(reach, &tuple(&[1, 3])).unwrap();
+    let premises: Vec<_> = proof
+        .premises
+        .iter()
+        .map(|p| (p.relation, &p.tuple))
+        .collect();
+    assert_eq!(
+        premises,
+        [(reach, &tuple(&[1, 2])), (edge, &tuple(&[2, 3]))]
+    );
+    let reach_1_2 = &proof.premises[0];
+    assert_eq!(reach_1_2.premises[0].tuple, tuple(&[1, 2]));
+    assert!(reach_1_2.premises[0].rule.is_none());
+
+    // an aggregate is derived from every tuple in its group
+    let proof = ctx.explain(deg, &tuple(&[1, 2])).unwrap();
+    let premises: Vec<_> = proof.premises.iter().map(|p| &p.tuple).collect();
+    assert_eq!(premises, [&tuple(&[1, 2]), &tuple(&[1, 3])]);
+
+    // rolling back a transaction puts the derivations back, even if it
+    // turned provenance off
+    let premises = |ctx: &DatalogContext| -> Vec<(Symbol, Vec<Value>)> {
+        let proof = ctx.explain(reach, &tuple(&[1, 3])).unwrap();
+        proof
+            .premises
+            .into_iter()
+            .map(|p| (p.relation, p.tuple))
+            .collect()
+    };
+    let before = premises(&ctx);
+    let mut tx = ctx.begin();
+    tx.retract_many(edge, &tuple(&[2, 3])).unwrap();
+    tx.parse_and_eval("edge(1, 3).").unwrap();
+    assert_eq!(premises(&tx), [(edge, tuple(&[1, 3]))]);
+    tx.rollback();
+    assert_eq!(premises(&ctx), before);
+    let mut tx = ctx.begin();
+    tx.retract_many(edge, &tuple(&[2, 3])).unwrap();
+    tx.set_provenance(false);
+    tx.rollback();
+    assert_eq!(premises(&ctx), before);
+}
+
+#[test]
+fn test_lattice() {
+    let decls = "
+        .decl edge(a: i32, b: i32).
+        .decl label(a: i32, l: i32).
+        .decl component(a: i32, l: i32).
+    ";
+    let program = "
+        component(a, l) :- label(a, l).
+        component(b, l) :- component(a, l), edge(a, b).
+        component(a, l) :- component(b, l), edge(a, b).
+
+        label(1, 1). label(2, 2). label(3, 3). label(4, 4). label(5, 5).
+        edge(2, 3). edge(3, 4). edge(4, 2). edge(5, 1).
+    ";
+    let component = Symbol::new("component");
+    let mut ctx = DatalogContext::default();
+    ctx.parse_and_eval(decls).unwrap();
+    ctx.set_lattice(component, Min);
+    ctx.parse_and_eval(program).unwrap();
+
+    let mut components = ctx.collect::<2>(component);
+    components.sort();
+    let expected = [[1, 1], [2, 2], [3, 2], [4, 2], [5, 1]];
+    let expected: Vec<[Value; 2]> = expected.iter().map(|t| t.map(|v| v.to_value())).collect();
+    assert_eq!(components, expected);
+
+    ctx.parse_and_eval("label(6, 0). edge(6, 4).").unwrap();
+    let mut components = ctx.collect::<2>(component);
+    components.sort();
+    let expected = [[1, 1], [2, 0], [3, 0], [4, 0], [5, 1], [6, 0]];
+    let expected: Vec<[Value; 2]> = expected.iter().map(|t| t.map(|v| v.to_value())).collect();
+    assert_eq!(components, expected);
+}
+
+#[test]
+fn test_magic_sets() {
+    let rules = "
+        .decl edge(a: i32, b: i32).
+        .decl node(a: i32).
+        .decl reach(a: i32, b: i32).
+        .decl far(a: i32, b: i32).
+        .decl unreached(a: i32, b: i32).
+
+        reach(a, b) :- edge(a, b).
+        reach(a, c) :- reach(a, b), edge(b, c).
+        far(a, c) :- reach(a, b), reach(b, c).
+        unreached(a, b) :- node(a), node(b), !reach(a, b).
+    ";
+    let (edge, node) = (Symbol::new("edge"), Symbol::new("node"));
+    let (reach, far) = (Symbol::new("reach"), Symbol::new("far"));
+    let goal = |relation: &str, terms: &[Option<i32>]| Atom {
+        relation: Symbol::new(relation),
+        terms: terms
+            .iter()
+            .enumerate()
+            .map(|(i, t)| match t {
+                Some(v) => Term::Value(v.to_value()),
+                None => Term::Variable(Symbol::new(format!("x{}", i))),
+            })
+            .collect(),
+    };
+    let goals = [
+        goal("reach", &[Some(1), None]),
+        goal("reach", &[None, Some(3)]),
+        goal("far", &[Some(1), None]),
+        goal("far", &[None, Some(4)]),
+        goal("unreached", &[Some(4), None]),
+        goal("reach", &[None, None]),
+    ];
+
+    let context = |magic_sets| {
+        let mut ctx = DatalogContext::default();
+        ctx.set_magic_sets(magic_sets);
+        ctx.parse_and_eval(rules).unwrap();
+        // two chains, 1 -> 2 -> 3 -> 4 and 10 -> 11 -> 12 -> 10
+        let edges = [1, 2, 2, 3, 3, 4, 10, 11, 11, 12, 12, 10];
+        let edges: Vec<Value> = edges.iter().map(|v: &i32| v.to_value()).collect();
+        ctx.insert_many(edge, &edges);
+        let nodes: Vec<Value> = [1, 2, 3, 4, 10]
+            .iter()
+            .map(|v: &i32| v.to_value())
+            .collect();
+        ctx.insert_many(node, &nodes);
+        ctx
+    };
+
+    let mut magic = context(true);
+    let mut full = context(false);
+    for goal in &goals[..4] {
+        let mut answers = magic.answer(goal).unwrap();
+        answers.sort();
+        let mut expected = full.answer(goal).unwrap();
+        expected.sort();
+        assert!(!expected.is_empty());
+        assert_eq!(answers, expected, "{:?}", goal);
+    }
+    // the rewritten programs ran on their own
+    assert!(magic.collect::<2>(reach).is_empty());
+    assert!(magic.collect::<2>(far).is_empty());
+
+    for goal in &goals[4..] {
+        let mut answers = magic.answer(goal).unwrap();
+        answers.sort();
+        let mut expected = full.answer(goal).unwrap();
+        expected.sort();
+        assert_eq!(answers, expected, "{:?}", goal);
+    }
+
+    // a program that only asks queries uses them even when they are off
+    let mut ctx = DatalogContext::default();
+    let program = "edge(1, 2). edge(2, 3). edge(5, 6). ?- reach(2, x).";
+    let answers = ctx.parse_and_eval(&(rules.to_string() + program)).unwrap();
+    assert_eq!(answers[0].len(), 1);
+    assert!(ctx.collect::<2>(reach).is_empty());
+}
+
+#[test]
+fn test_query() {
+    let program = "
+        .decl edge(a: i32, b: i32).
+        .decl reach(a: i32, b: i32).
+
+        edge(1, 2). edge(2, 3). edge(3, 1). edge(3, 4).
+
+        reach(a, b) :- edge(a, b).
+        reach(a, c) :- reach(a, b), edge(b, c).
+
+        ?- reach(4, x).
+        ?- reach(x, 4), !edge(x, 4).
+    ";
+    let x = Symbol::new("x");
+    let values = |vals: &[i32]| -> Vec<Value> { vals.iter().map(|&v| v.to_value()).collect() };
+    let column = |answers: &[datastick::Bindings], var| -> Vec<Value> {
+        let mut column: Vec<Value> = answers.iter().map(|b| b[&var]).collect();
+        column.sort();
+        column
+    };
+
+    let mut ctx = DatalogContext::default();
+    let answers = ctx.parse_and_eval(program).unwrap();
+    assert_eq!(answers.len(), 2);
+    assert!(answers[0].is_empty());
+    assert_eq!(column(&answers[1], x), values(&[1, 2]));
+
+    for magic_sets in [false, true] {
+        ctx.set_magic_sets(magic_sets);
+        let answers = ctx.parse_and_query("reach(1, x)").unwrap();
+        assert_eq!(column(&answers, x), values(&[1, 2, 3, 4]));
+        // wildcards and repeated variables are not bound twice
+        let answers = ctx.parse_and_query("reach(x, x), edge(x, _)").unwrap();
+        assert!(answers.iter().all(|b| b.len() == 1));
+        assert_eq!(column(&answers, x), values(&[1, 2, 3]));
+    }
+
+    let answers = ctx.parse_and_query("edge(x, y), edge(y, x)").unwrap();
+    assert!(answers.is_empty());
+    let answers = ctx.parse_and_query("edge(x, y), reach(y, x)").unwrap();
+    assert_eq!(answers.len(), 3);
+    assert!(ctx.parse_and_query("edge(x, 1), !reach(y, x)").is_err());
+}
+
+#[test]
+fn test_budget() {
+    let decls = "
+        .decl edge(a: i32, b: i32).
+        .decl reach(a: i32, b: i32).
+        .decl unreached(a: i32).
+
+        reach(a, b) :- edge(a, b).
+        reach(a, c) :- reach(a, b), edge(b, c).
+        unreached(b) :- edge(a, b), !reach(1, b).
+    ";
+    let (edge, reach, unreached) = (
+        Symbol::new("edge"),
+        Symbol::new("reach"),
+        Symbol::new("unreached"),
+    );
+    // a chain takes an iteration per edge to walk
+    let chain: Vec<Value> = (1..50)
+        .flat_map(|i: i32| [i, i + 1])
+        .map(|v| v.to_value())
+        .collect();
+    let context = || {
+        let mut ctx = DatalogContext::default();
+        ctx.parse_and_eval(decls).unwrap();
+        ctx.insert_many(edge, &chain);
+        ctx
+    };
+    let mut full = context();
+    let outcome = full.run();
+    assert!(outcome.is_fixpoint());
+    assert_eq!(outcome.derived, full.collect::<2>(reach).len());
+
+    let limits = [
+        (
+            Budget {
+                max_iterations: Some(5),
+                ..Budget::default()
+            },
+            Limit::Iterations,
+        ),
+        (
+            Budget {
+                max_derived: Some(100),
+                ..Budget::default()
+            },
+            Limit::Derived,
+        ),
+        (
+            Budget {
+                timeout: Some(Duration::ZERO),
+                ..Budget::default()
+            },
+            Limit::Time,
+        ),
+    ];
+    for (budget, limit) in limits {
+        let mut ctx = context();
+        ctx.set_budget(budget);
+        let outcome = ctx.run();
+        assert_eq!(outcome.stopped, Some(limit));
+        let partial = ctx.collect::<2>(reach);
+        assert_eq!(outcome.derived, partial.len());
+        assert!(partial.len() < full.collect::<2>(reach).len());
+        assert!(partial.iter().all(|t| full.collect::<2>(reach).contains(t)));
+        assert_eq!(
+            ctx.parse_and_eval("edge(50, 1).").unwrap_err(),
+            Error::StoppedEarly(limit)
+        );
+
+        // picking up where it stopped reaches the same fixpoint
+        ctx.set_budget(Budget::default());
+        assert!(ctx.run().is_fixpoint());
+        full.insert_many(edge, &[50.to_value(), 1.to_value()]);
+        full.run();
+        let mut reached = ctx.collect::<2>(reach);
+        reached.sort();
+        let mut expected = full.collect::<2>(reach);
+        expected.sort();
+        assert_eq!(reached, expected);
+        full.retract_many(edge, &[50.to_value(), 1.to_value()])
+            .unwrap();
+    }
+
+    // cancelling stops the next run, and only that one
+    let mut ctx = context();
+    let token = ctx.cancellation_token();
+    std::thread::spawn(move || token.cancel()).join().unwrap();
+    assert_eq!(ctx.run().stopped, Some(Limit::Cancelled));
+    assert!(ctx.collect::<2>(reach).is_empty());
+    assert!(ctx.run().is_fixpoint());
+    assert_eq!(
+        ctx.collect::<2>(reach).len(),
+        full.collect::<2>(reach).len()
+    );
+
+    // a retraction that stops early leaves everything to be recomputed
+    let more: Vec<Value> = (100..120)
+        .flat_map(|i: i32| [i, i + 1])
+        .map(|v| v.to_value())
+        .collect();
+    let cut = [25.to_value(), 26.to_value()];
+    ctx.insert_many(edge, &more);
+    ctx.set_budget(Budget {
+        max_iterations: Some(3),
+        ..Budget::default()
+    });
+    assert_eq!(
+        ctx.retract_many(edge, &cut).unwrap().stopped,
+        Some(Limit::Iterations)
+    );
+    ctx.set_budget(Budget::default());
+    assert!(ctx.run().is_fixpoint());
+    full.insert_many(edge, &more);
+    assert!(full.retract_many(edge, &cut).unwrap().is_fixpoint());
+    let mut reached = ctx.collect::<2>(reach);
+    reached.sort();
+    let mut expected = full.collect::<2>(reach);
+    expected.sort();
+    assert_eq!(reached, expected);
+    let mut unreachable = ctx.collect::<1>(unreached);
+    unreachable.sort();
+    let mut expected = full.collect::<1>(unreached);
+    expected.sort();
+    assert_eq!(unreachable, expected);
+}
+
+#[test]
+fn test_budget_within_iteration() {
+    let decls = "
+        .decl n(a: i32).
+        .decl x(a: i32, b: i32, c: i32).
+
+        x(a, b, c) :- n(a), n(b), n(c).
+    ";
+    let (n, x) = (Symbol::new("n"), Symbol::new("x"));
+    // a single iteration that joins 120^3 substitutions
+    let context = |budget| {
+        let mut ctx = DatalogContext::default();
+        ctx.parse_and_eval(decls).unwrap();
+        let values: Vec<Value> = (0..120).map(|i: i32| i.to_value()).collect();
+        ctx.insert_many(n, &values);
+        ctx.set_budget(budget);
+        ctx
+    };
+    let check = |ctx: &mut DatalogContext, limit| {
+        let outcome = ctx.run();
+        assert_eq!(outcome.stopped, Some(limit));
+        // the iteration that was stopped derives nothing
+        assert_eq!(outcome.derived, 0);
+        assert!(ctx.collect::<3>(x).is_empty());
+    };
+
+    let mut ctx = context(Budget {
+        max_derived: Some(1000),
+        ..Budget::default()
+    });
+    check(&mut ctx, Limit::Derived);
+    let mut ctx = context(Budget {
+        timeout: Some(Duration::from_millis(1)),
+        ..Budget::default()
+    });
+    check(&mut ctx, Limit::Time);
+
+    let mut ctx = context(Budget::default());
+    let token = ctx.cancellation_token();
+    let cancel = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(1));
+        token.cancel();
+    });
+    check(&mut ctx, Limit::Cancelled);
+    cancel.join().unwrap();
+    assert!(!ctx.cancellation_token().is_cancelled());
+}
+
+#[test]
+fn test_transaction() {
+    let mut ctx = DatalogContext::default();
+    ctx.parse_and_eval(
+        "
+        .decl edge(a: i32, b: i32).
+        .decl reach(a: i32, b: i32).
+        .decl cycle(a: i32).
+
+        reach(a, b) :- edge(a, b).
+        reach(a, c) :- reach(a, b), edge(b, c).
+        cycle(a) :- reach(a, a).
+
+        edge(1, 2). edge(2, 3). edge(3, 4).
+        ",
+    )
+    .unwrap();
+    let (edge, reach, cycle) = (
+        Symbol::new("edge"),
+        Symbol::new("reach"),
+        Symbol::new("cycle"),
+    );
+    let sorted = |ctx: &DatalogContext, sym| {
+        let mut tuples = ctx.collect::<2>(sym);
+        tuples.sort();
+        tuples
+    };
+    let before = sorted(&ctx, reach);
+
+    // a batch that would close a cycle is thrown away
+    let mut tx = ctx.begin();
+    tx.parse_and_eval("edge(4, 5). edge(5, 1). edge(3, 1).")
+        .unwrap();
+    tx.retract_many(edge, &[4.to_value(), 5.to_value()])
+        .unwrap();
+    assert_eq!(tx.collect::<1>(cycle).len(), 3);
+    tx.rollback();
+    assert_eq!(sorted(&ctx, reach), before);
+    assert!(ctx.collect::<1>(cycle).is_empty());
+
+    // the strata remember what they saw before, so the next run is the same
+    ctx.insert_many(edge, &[4.to_value(), 5.to_value()]);
+    ctx.run().fixpoint().unwrap();
+    let mut expected = DatalogContext::default();
+    expected
+        .parse_and_eval(
+            "
+            .decl edge(a: i32, b: i32).
+            .decl reach(a: i32, b: i32).
+            reach(a, b) :- edge(a, b).
+            reach(a, c) :- reach(a, b), edge(b, c).
+            edge(1, 2). edge(2, 3). edge(3, 4). edge(4, 5).
+            ",
+        )
+        .unwrap();
+    assert_eq!(sorted(&ctx, reach), sorted(&expected, reach));
+
+    // relations and rules added in a transaction go away too, committing
+    // keeps them
+    let back = ".decl back(a: i32, b: i32). back(b, a) :- reach(a, b).";
+    ctx.begin().parse_and_eval(back).unwrap();
+    ctx.insert_many(edge, &[5.to_value(), 1.to_value()]);
+    ctx.run().fixpoint().unwrap();
+    assert_eq!(ctx.collect::<1>(cycle).len(), 5);
+    let mut tx = ctx.begin();
+    tx.parse_and_eval(back).unwrap();
+    tx.commit();
+    assert_eq!(ctx.collect::<2>(Symbol::new("back")).len(), 25);
+}
+
+#[test]
+fn test_save_and_load() {
+    let mut ctx = DatalogContext::default();
+    ctx.parse_and_eval(
+        "
+        .decl edge(a: i32, b: i32).
+        .decl reach(a: i32, b: i32).
+
+        reach(a, b) :- edge(a, b).
+        reach(a, c) :- reach(a, b), edge(b, c).
+
+        edge(1, 2). edge(2, 3). edge(3, 1). edge(3, 4).
+        ",
+    )
+    .unwrap();
+    let (edge, reach) = (Symbol::new("edge"), Symbol::new("reach"));
+    let path = std::env::temp_dir().join(format!("datastick-run-{}.db", std::process::id()));
+    ctx.db().save(&path).unwrap();
+
+    let db = Database::load(&path).unwrap();
+    let mut loaded = vec![];
+    db.relations[&reach].for_each(|t| loaded.push(t.to_vec()));
+    let mut expected = vec![];
+    ctx.for_each(reach, |t| expected.push(t.to_vec()));
+    assert_eq!(loaded, expected);
+    assert_eq!(
+        db.relations[&reach].schema,
+        ctx.db().relations[&reach].schema
+    );
+
+    // a context loaded with the rules carries on incrementally
+    let rules = "
+        .decl edge(a: i32, b: i32).
+        .decl reach(a: i32, b: i32).
+        .decl cycle(a: i32).
+
+        reach(a, b) :- edge(a, b).
+        reach(a, c) :- reach(a, b), edge(b, c).
+    ";
+    let mut loaded = DatalogContext::load(&path, rules).unwrap();
+    let mismatched = ".decl edge(a: i32).";
+    assert_eq!(
+        DatalogContext::load(&path, mismatched).err(),
+        Some(Error::SchemaMismatch(edge))
+    );
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.run().derived, 0);
+    loaded.parse_and_eval("cycle(a) :- reach(a, a).").unwrap();
+    assert_eq!(loaded.collect::<1>(Symbol::new("cycle")).len(), 3);
+    let sorted = |ctx: &DatalogContext| {
+        let mut tuples = ctx.collect::<2>(reach);
+        tuples.sort();
+        tuples
+    };
+    for ctx in [&mut ctx, &mut loaded] {
+        ctx.insert_many(edge, &[4.to_value(), 5.to_value()]);
+        assert_eq!(ctx.run().derived, 4);
+        ctx.retract_many(edge, &[3.to_value(), 1.to_value()])
+            .unwrap();
+    }
+    assert_eq!(sorted(&loaded), sorted(&ctx));
+}
+
+#[test]
+fn test_no_positive_atoms() {
+    let mut ctx = DatalogContext::default();
+    ctx.parse_and_eval(
+        "
+        .decl bad(a: i32).
+        .decl ok(a: i32).
+        .decl one(a: i32).
+
+        bad(2).
+        ok(1) :- !bad(1).
+        ok(2) :- !bad(2).
+        one(7) :- .
+        ",
+    )
+    .unwrap();
+    let (bad, ok, one) = (Symbol::new("bad"), Symbol::new("ok"), Symbol::new("one"));
+    assert_eq!(ctx.collect::<1>(ok), [[1.to_value()]]);
+
+    // the rules fire again when what they negate changes
+    ctx.insert_many(bad, &[1.to_value()]);
+    ctx.retract_many(bad, &[2.to_value()])
+        .unwrap()
+        .fixpoint()
+        .unwrap();
+    assert_eq!(ctx.collect::<1>(ok), [[2.to_value()]]);
+
+    // and don't lose their tuples with what they never read
+    ctx.parse_and_eval(
+        "
+        .decl edge(a: i32, b: i32).
+        .decl reach(a: i32).
+        reach(1) :- .
+        reach(b) :- reach(a), edge(a, b).
+        edge(1, 2). edge(2, 3).
+        ",
+    )
+    .unwrap();
+    let reach = Symbol::new("reach");
+    let edge = Symbol::new("edge");
+    ctx.retract_many(edge, &[1.to_value(), 2.to_value()])
+        .unwrap();
+    assert_eq!(ctx.collect::<1>(reach), [[1.to_value()]]);
+    assert_eq!(ctx.collect::<1>(one), [[7.to_value()]]);
+}